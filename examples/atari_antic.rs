@@ -44,11 +44,8 @@ fn update(
             let col_agg = atari_data
                 .collisions_data
                 .as_ref()
-                .map(|cd| {
-                    let guard = cd.inner.read();
-                    guard.data.iter().cloned().reduce(|a, v| a | v).unwrap()
-                })
-                .unwrap_or(0);
+                .map(|cd| cd.frame())
+                .unwrap_or_default();
 
             let mut inner = atari_data.inner.write();
             let c = &mut inner.memory[32 * 240 + 1024];
@@ -56,7 +53,7 @@ fn update(
             let c = &mut inner.memory[32 * 240 + 1024 + 31];
             *c = c.wrapping_add(1);
 
-            let text = format!("collisions: {:x}", col_agg);
+            let text = format!("collisions: {:x}", col_agg.0);
             text.as_bytes()
                 .iter()
                 .cloned()
@@ -107,28 +104,18 @@ fn setup(
         scan_line: start_scan_line,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
     antic_data.insert_mode_line(&ModeLineDescr {
         mode: 2,
         scan_line: start_scan_line + 8,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
     let voffs = antic_data.reserve_antic_memory(40, &mut |data| {
         data.copy_from_slice(&[
@@ -143,14 +130,9 @@ fn setup(
         scan_line: start_scan_line + 16,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
     let voffs = antic_data.reserve_antic_memory(40, &mut |data| {
         data.copy_from_slice(&[
@@ -165,14 +147,9 @@ fn setup(
         scan_line: start_scan_line + 24,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
 
     antic_data.insert_mode_line(&ModeLineDescr {
@@ -180,28 +157,18 @@ fn setup(
         scan_line: start_scan_line + 32,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs0,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
     antic_data.insert_mode_line(&ModeLineDescr {
         mode: 0,
         scan_line: start_scan_line + 40,
         width: 256,
         height: 8,
-        n_bytes: 0,
-        line_voffset: 0,
-        data_offset: 0,
-        chbase: 0,
-        pmbase: 0,
-        hscrol: 0,
         video_memory_offset: voffs,
         charset_memory_offset: coffs,
+        ..Default::default()
     });
 
     let voffs = antic_data.reserve_antic_memory(40, &mut |data| {
@@ -295,6 +262,7 @@ fn main() {
     app.insert_resource(MemOffsets([0; 24]))
         .add_plugin(AtariAnticPlugin {
             collisions: COLLISIONS,
+            ..Default::default()
         })
         .add_startup_system(setup)
         .add_system(update);
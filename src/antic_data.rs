@@ -1,8 +1,17 @@
-use std::sync::Arc;
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+};
 
 use bevy::{
+    core::Time,
+    ecs::prelude::*,
     math::vec2,
-    prelude::Handle,
+    asset::HandleId,
+    prelude::{AssetEvent, Assets, EventReader, Handle},
     reflect::TypeUuid,
     render::{
         mesh::{Indices, Mesh},
@@ -10,31 +19,227 @@ use bevy::{
         renderer::RenderDevice,
         texture::Image,
     },
+    utils::HashMap,
 };
 use futures_lite::future;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use wgpu::{BufferDescriptor, BufferUsages, PrimitiveTopology};
 
 use super::resources::{AnticConfig, AtariPalette};
-use crate::ModeLineDescr;
+use crate::{
+    collisions,
+    display_list::{FIRST_SCAN_LINE, LAST_SCAN_LINE, SCAN_LINES},
+    AnticRegs, Collisions, DisplayList, ImageReadback, ModeLineDescr, Snapshot, VideoStandard,
+};
+
+/// Interpretation of antic memory following GTIA registers in `AnticDataInner::memory`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMode {
+    /// Memory is allocated with `AnticData::reserve_antic_memory`,
+    /// mode line offsets are relative to the beginning of reserved memory.
+    #[default]
+    Reserved,
+    /// Memory mirrors the whole 64 KB Atari address space.
+    /// `video_memory_offset`, `chbase` and `pmbase` of mode lines are real Atari addresses,
+    /// screen data of a mode line wraps on 4K boundary like ANTIC memory scan counter.
+    AddressSpace,
+}
+
+impl MemoryMode {
+    pub fn memory_size(&self) -> usize {
+        match self {
+            MemoryMode::Reserved => 256 * 11 * 4 * 4,
+            MemoryMode::AddressSpace => 0x10000,
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct AnticDataInner {
     pub scanlines: usize,
     pub memory: Vec<u8>,
     pub memory_used: usize,
+    pub memory_mode: MemoryMode,
     pub palette: AtariPalette,
+    pub video_standard: VideoStandard,
     pub positions: Vec<[f32; 3]>,
     pub custom: Vec<[f32; 4]>,
     pub uvs: Vec<[f32; 2]>,
     pub indices: Vec<u16>,
+    /// mode lines inserted since the last `AnticData::clear`
+    pub mode_lines: Vec<ModeLineDescr>,
+}
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+/// Buffer collisions are read back from.
+pub trait ReadbackBuffer {
+    /// Requests mapping of the whole buffer for reading.
+    fn map_read(&self) -> MapFuture;
+    /// Calls `f` with contents of the mapped buffer.
+    fn read_mapped(&self, f: &mut dyn FnMut(&[u8]));
+    fn unmap(&self);
+}
+
+impl ReadbackBuffer for Buffer {
+    fn map_read(&self) -> MapFuture {
+        Box::pin(self.slice(..).map_async(wgpu::MapMode::Read))
+    }
+
+    fn read_mapped(&self, f: &mut dyn FnMut(&[u8])) {
+        f(&self.slice(..).get_mapped_range())
+    }
+
+    fn unmap(&self) {
+        Buffer::unmap(self)
+    }
+}
+
+/// State of single collisions readback buffer.
+pub enum ReadbackSlot {
+    /// Buffer may be used as destination of the next copy.
+    Free,
+    /// Collisions of `frame` were copied to the buffer.
+    Copied { frame: u64 },
+    /// Buffer mapping was requested and is not finished yet.
+    Mapping {
+        frame: u64,
+        map_future: Mutex<MapFuture>,
+    },
+    /// Buffer is mapped and its data can be published.
+    Mapped { frame: u64 },
+}
+
+pub struct CollisionsDataInner<B = Buffer> {
+    /// collisions detected on every visible scan line
+    pub data: [u64; SCAN_LINES],
+    /// collision registers as seen at the end of every visible scan line (honouring HITCLR)
+    pub registers: [u64; SCAN_LINES],
+    pub buffers: Vec<B>,
+    pub slots: Vec<ReadbackSlot>,
+    /// scan lines with HITCLR written, for frame copied to corresponding buffer
+    pub hitclr: Vec<[bool; SCAN_LINES]>,
+    /// scan lines with HITCLR written, for the most recently extracted frame
+    pub pending_hitclr: [bool; SCAN_LINES],
+    /// buffer used as copy destination by the current frame, `None` if all buffers are in flight
+    pub buffer_index: Option<usize>,
+    /// number of the current frame, incremented by `CollisionsData::read_collisions`
+    pub frame: u64,
+    /// number of the frame `data` and `registers` come from
+    pub data_frame: Option<u64>,
+    /// maximum number of frames published data may lag behind the previous frame,
+    /// `None` means readback never blocks
+    pub max_latency: Option<u64>,
 }
 
-pub struct CollisionsDataInner {
-    pub data: [u64; 240],
-    pub buffers: Vec<Buffer>,
-    pub buffer_index: usize,
+impl<B: ReadbackBuffer> CollisionsDataInner<B> {
+    fn new(buffers: Vec<B>) -> Self {
+        // blocking wait is not possible on the web
+        let max_latency = if cfg!(target_arch = "wasm32") {
+            None
+        } else {
+            Some(0)
+        };
+        Self {
+            data: [0; SCAN_LINES],
+            registers: [0; SCAN_LINES],
+            hitclr: vec![[false; SCAN_LINES]; buffers.len()],
+            pending_hitclr: [false; SCAN_LINES],
+            slots: buffers.iter().map(|_| ReadbackSlot::Free).collect(),
+            buffers,
+            buffer_index: Some(0),
+            frame: 0,
+            data_frame: None,
+            max_latency,
+        }
+    }
+
+    /// Advances readback slots, `poll` is called once with `true` if it has to wait
+    /// for the device to finish pending mappings.
+    fn update(&mut self, poll: impl FnOnce(bool)) {
+        self.frame += 1;
+
+        for (buffer, slot) in self.buffers.iter().zip(self.slots.iter_mut()) {
+            if let ReadbackSlot::Copied { frame } = *slot {
+                *slot = ReadbackSlot::Mapping {
+                    frame,
+                    map_future: Mutex::new(buffer.map_read()),
+                };
+            }
+        }
+
+        let current_frame = self.frame;
+        let max_latency = self.max_latency;
+        let must_wait =
+            |frame: u64| matches!(max_latency, Some(l) if frame + 1 + l <= current_frame);
+        let wait = self.slots.iter().any(|slot| match slot {
+            ReadbackSlot::Mapping { frame, .. } => must_wait(*frame),
+            _ => false,
+        });
+        poll(wait);
+
+        for slot in self.slots.iter_mut() {
+            let next = match slot {
+                ReadbackSlot::Mapping { frame, map_future } => {
+                    let map_future = map_future.get_mut();
+                    let result = if must_wait(*frame) {
+                        Some(future::block_on(map_future))
+                    } else {
+                        future::block_on(future::poll_once(map_future))
+                    };
+                    match result {
+                        Some(Ok(())) => ReadbackSlot::Mapped { frame: *frame },
+                        Some(Err(_)) => ReadbackSlot::Free,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            *slot = next;
+        }
+
+        let mut mapped: Vec<(u64, usize)> = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| match slot {
+                ReadbackSlot::Mapped { frame } => Some((*frame, index)),
+                _ => None,
+            })
+            .collect();
+        mapped.sort_unstable();
+
+        for (frame, index) in mapped {
+            let buffer = &self.buffers[index];
+            if self.data_frame < Some(frame) {
+                let dest = &mut self.data;
+                buffer.read_mapped(&mut |data| {
+                    let width = crate::COLLISIONS_AGG_TEXTURE_SIZE.width as usize
+                        * crate::COLLISIONS_AGG__BYTES_PER_PIXEL;
+                    for (y, row) in data.chunks_exact(width).enumerate() {
+                        let row = row.chunks_exact(8).take(SCAN_LINES);
+                        for (x, bytes) in row.enumerate() {
+                            let value = u64::from_le_bytes(bytes.try_into().unwrap());
+                            dest[x] = if y == 0 { value } else { dest[x] | value };
+                        }
+                    }
+                });
+                let prev = Collisions(self.registers[239]);
+                collisions::accumulate(prev, &self.data, &self.hitclr[index], &mut self.registers);
+                self.data_frame = Some(frame);
+            }
+            buffer.unmap();
+            self.slots[index] = ReadbackSlot::Free;
+        }
+
+        let len = self.slots.len();
+        let prev_index = self.buffer_index.unwrap_or(len - 1);
+        self.buffer_index = (1..=len)
+            .map(|i| (prev_index + i) % len)
+            .find(|&index| matches!(self.slots[index], ReadbackSlot::Free));
+    }
 }
+
 #[derive(Clone)]
 pub struct CollisionsData {
     pub inner: Arc<RwLock<CollisionsDataInner>>,
@@ -43,48 +248,77 @@ pub struct CollisionsData {
 impl CollisionsData {
     pub fn new(buffers: Vec<Buffer>) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(CollisionsDataInner {
-                data: [0; 240],
-                buffers,
-                buffer_index: 0,
-            })),
+            inner: Arc::new(RwLock::new(CollisionsDataInner::new(buffers))),
         }
     }
+
+    /// Publishes collisions of already rendered frames and starts readback of the recent ones.
+    ///
+    /// Should be called once per frame. It blocks only if results older than `max_latency`
+    /// frames are not available yet.
     pub fn read_collisions(&self, render_device: &RenderDevice) {
+        self.inner.write().update(|wait| {
+            render_device.poll(if wait {
+                wgpu::Maintain::Wait
+            } else {
+                wgpu::Maintain::Poll
+            })
+        });
+    }
+
+    /// Marks the current frame as copied to `buffer_index` buffer, together with its HITCLR
+    /// scan lines. Called by render graph node.
+    pub(crate) fn copied<B>(inner: &mut CollisionsDataInner<B>, index: usize) {
+        inner.slots[index] = ReadbackSlot::Copied { frame: inner.frame };
+        inner.hitclr[index] = inner.pending_hitclr;
+    }
+
+    /// Sets maximum number of frames published collisions may lag behind the previous frame.
+    /// With `None` readback never blocks and results are published as soon as they are ready.
+    pub fn set_max_latency(&self, max_latency: Option<u64>) {
+        self.inner.write().max_latency = max_latency;
+    }
+
+    /// Number of the frame published collisions come from.
+    pub fn data_frame(&self) -> Option<u64> {
+        self.inner.read().data_frame
+    }
+
+    /// Number of the current frame.
+    pub fn current_frame(&self) -> u64 {
+        self.inner.read().frame
+    }
+
+    /// Records scan lines with HITCLR written, used by frames copied until the next call.
+    pub fn set_hitclr(&self, gtia_regs: &[u8]) {
         let mut inner = self.inner.write();
-        let len = inner.buffers.len();
-
-        // collisions delay
-        // TODO: make it configurable? It seems current setting causes some bugs in RiverRaid
-        let offs = 0;
-
-        let index = (inner.buffer_index + len - offs) % inner.buffers.len();
-        inner.buffer_index = (inner.buffer_index + 1) % inner.buffers.len();
-        let buffer = inner.buffers[index].clone();
-        // bevy::log::info!("reading buffer {}", inner.buffer_index);
-        let slice = buffer.slice(..);
-        let map_future = slice.map_async(wgpu::MapMode::Read);
-        render_device.poll(wgpu::Maintain::Wait);
-        future::block_on(map_future).unwrap();
-        {
-            let buffer_view = slice.get_mapped_range();
-            let data: &[u8] = &buffer_view;
-            let data =
-                unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u64, data.len() / 8) };
-            let dest = &mut inner.data;
-            for y in 0..crate::COLLISIONS_AGG_TEXTURE_SIZE.height as usize {
-                if y == 0 {
-                    for x in 0..240 {
-                        dest[x] = data[y << 8 | x];
-                    }
-                } else {
-                    for x in 0..240 {
-                        dest[x] |= data[y << 8 | x];
-                    }
-                }
-            }
+        for (scan_line, hitclr) in inner.pending_hitclr.iter_mut().enumerate() {
+            *hitclr = gtia_regs[scan_line * 32 + 0x1e] > 0;
         }
-        buffer.unmap();
+    }
+
+    /// Value of collision registers read by a program at the end of visible scan line `scan_line`.
+    pub fn collisions_at(&self, scan_line: usize) -> Collisions {
+        Collisions(self.inner.read().registers[scan_line])
+    }
+
+    /// Collisions detected on visible scan line `scan_line` (0..`SCAN_LINES`).
+    pub fn scan_line(&self, scan_line: usize) -> Collisions {
+        Collisions(self.inner.read().data[scan_line])
+    }
+
+    /// Collisions aggregated over range of visible scan lines.
+    pub fn range(&self, scan_lines: Range<usize>) -> Collisions {
+        let inner = self.inner.read();
+        inner.data[scan_lines]
+            .iter()
+            .map(|v| Collisions(*v))
+            .collect()
+    }
+
+    /// Value of collision registers at the end of the frame.
+    pub fn frame(&self) -> Collisions {
+        self.collisions_at(239)
     }
 }
 
@@ -94,10 +328,11 @@ pub struct AnticData {
     pub main_image_handle: Handle<Image>,
     pub inner: Arc<RwLock<AnticDataInner>>,
     pub collisions_data: Option<CollisionsData>,
+    pub image_readback: Option<ImageReadback>,
     pub config: AnticConfig,
 }
 
-const GTIA_REGS_MEMORY: usize = 240 * 32;
+pub const GTIA_REGS_MEMORY: usize = SCAN_LINES * 32;
 
 impl AnticData {
     pub fn new(
@@ -105,8 +340,25 @@ impl AnticData {
         main_image_handle: Handle<Image>,
         collisions: bool,
     ) -> Self {
-        let mut memory = Vec::with_capacity(GTIA_REGS_MEMORY + 256 * 11 * 4 * 4);
-        memory.resize(memory.capacity(), 0);
+        Self::with_memory_mode(
+            render_device,
+            main_image_handle,
+            collisions,
+            MemoryMode::Reserved,
+        )
+    }
+
+    /// Creates data without GPU resources, to be rendered with `software::render`.
+    pub fn headless(memory_mode: MemoryMode) -> Self {
+        Self::create(Handle::default(), None, memory_mode)
+    }
+
+    pub fn with_memory_mode(
+        render_device: &RenderDevice,
+        main_image_handle: Handle<Image>,
+        collisions: bool,
+        memory_mode: MemoryMode,
+    ) -> Self {
         let buffer_desc = BufferDescriptor {
             label: Some("atari collisions buffer"),
             usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
@@ -126,24 +378,69 @@ impl AnticData {
         } else {
             None
         };
+        Self::create(main_image_handle, collisions_data, memory_mode)
+    }
+
+    fn create(
+        main_image_handle: Handle<Image>,
+        collisions_data: Option<CollisionsData>,
+        memory_mode: MemoryMode,
+    ) -> Self {
+        let mut memory = Vec::with_capacity(GTIA_REGS_MEMORY + memory_mode.memory_size());
+        memory.resize(memory.capacity(), 0);
         Self {
             main_image_handle,
             collisions_data,
+            image_readback: None,
             config: AnticConfig::default(),
             inner: Arc::new(RwLock::new(AnticDataInner {
                 scanlines: 0,
                 memory,
                 memory_used: 0,
+                memory_mode,
                 palette: AtariPalette::default(),
+                video_standard: VideoStandard::default(),
                 positions: Default::default(),
                 custom: Default::default(),
                 uvs: Default::default(),
                 indices: Default::default(),
+                mode_lines: Default::default(),
             })),
         }
     }
+    pub fn palette(&self) -> AtariPalette {
+        self.inner.read().palette
+    }
+
+    /// Replaces the palette, uploaded to GPU with the next frame.
+    pub fn set_palette(&mut self, palette: AtariPalette) {
+        self.inner.write().palette = palette;
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.inner.read().video_standard
+    }
+
+    /// Sets video standard and visible scan lines. Palette is replaced with the default one
+    /// of `standard`, unless a custom palette was set.
+    pub fn set_video_standard(&mut self, standard: VideoStandard) {
+        let mut inner = self.inner.write();
+        if inner.palette == AtariPalette::for_standard(inner.video_standard) {
+            inner.palette = AtariPalette::for_standard(standard);
+        }
+        inner.video_standard = standard;
+        let visible = standard.visible_scan_lines();
+        self.config.first_visible_line = (visible.start - FIRST_SCAN_LINE) as i32;
+        self.config.last_visible_line = (visible.end - FIRST_SCAN_LINE) as i32;
+    }
+
+    /// Enables copying of the main image to CPU memory with `ImageReadback`.
+    pub fn enable_image_readback(&mut self, render_device: &RenderDevice) {
+        self.image_readback = Some(ImageReadback::new(render_device));
+    }
+
     pub fn set_gtia_regs(&mut self, scan_line: usize, regs: &crate::GTIARegs) {
-        assert!(scan_line < 248);
+        assert!(scan_line < SCAN_LINES);
         assert!(std::mem::size_of::<crate::GTIARegs>() == 32);
         let mut inner = self.inner.write();
         let ptr = inner.memory.as_mut_ptr() as *mut crate::GTIARegs;
@@ -152,6 +449,7 @@ impl AnticData {
 
     pub fn reserve_antic_memory(&mut self, len: usize, cb: &mut dyn FnMut(&mut [u8])) -> usize {
         let mut inner = self.inner.write();
+        assert!(inner.memory_mode == MemoryMode::Reserved);
         let dst_offset = GTIA_REGS_MEMORY + inner.memory_used;
         assert!(dst_offset + len <= inner.memory.len());
         inner.memory_used += len;
@@ -160,6 +458,27 @@ impl AnticData {
         dst_offset - GTIA_REGS_MEMORY
     }
 
+    /// Reserves player / missile DMA area (1K for two-line, 2K for single-line resolution)
+    /// and returns value of PMBASE pointing to it, to be used in `ModeLineDescr::pmbase`.
+    pub fn reserve_pm_memory(&mut self, single_line: bool, cb: &mut dyn FnMut(&mut [u8])) -> u8 {
+        let len = if single_line { 2048 } else { 1024 };
+        {
+            let mut inner = self.inner.write();
+            inner.memory_used = inner.memory_used.next_multiple_of(len);
+        }
+        let offset = self.reserve_antic_memory(len, cb);
+        (offset >> 8) as u8
+    }
+
+    /// Copies `data` to Atari memory at `address`. Available in `MemoryMode::AddressSpace` only.
+    pub fn set_atari_memory(&mut self, address: usize, data: &[u8]) {
+        let mut inner = self.inner.write();
+        assert!(inner.memory_mode == MemoryMode::AddressSpace);
+        assert!(address + data.len() <= 0x10000);
+        let offset = GTIA_REGS_MEMORY + address;
+        inner.memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+
     pub fn clear(&mut self) {
         let mut inner = self.inner.write();
         inner.memory_used = 0;
@@ -168,6 +487,18 @@ impl AnticData {
         inner.custom.clear();
         inner.uvs.clear();
         inner.indices.clear();
+        inner.mode_lines.clear();
+    }
+
+    /// Writes state of the current frame in `Snapshot` format.
+    pub fn save_snapshot(&self, writer: impl Write) -> io::Result<()> {
+        Snapshot::capture(self).write(writer)
+    }
+
+    /// Replaces state of the current frame with snapshot read from `reader`.
+    pub fn load_snapshot(&mut self, reader: impl Read) -> io::Result<()> {
+        Snapshot::read(reader)?.restore(self);
+        Ok(())
     }
 
     pub fn create_collisions_agg_mesh(&self) -> Mesh {
@@ -211,25 +542,12 @@ impl AnticData {
         let mut uvs = inner.uvs.clone();
         let mut custom = inner.custom.clone();
         let mut indices = inner.indices.clone();
-        let scan_line = inner.scanlines as usize + 8;
-        if scan_line < 248 {
-            // hack for paused mode
-            // to display scan_line we need to add additional empty rect
-            // on the end of mesh with height = 1
-            // TODO: move this to some postprocessing pass
+        if let Some(mode_line) = paused_mode_line(inner.scanlines) {
             let index_offset = positions.len() as u16;
 
-            let mode_line = ModeLineDescr {
-                mode: 0,
-                scan_line,
-                width: 384,
-                height: 1,
-                ..Default::default()
-            };
-
             push_positions(&mut positions, &mode_line);
             push_uvs(&mut uvs);
-            push_custom(&mut custom, &mode_line);
+            push_custom(&mut custom, &mode_line, 0);
             push_indices(&mut indices, index_offset);
         }
 
@@ -246,11 +564,133 @@ impl AnticData {
         let index_offset = inner.positions.len() as u16;
 
         inner.scanlines = mode_line.scan_line + mode_line.height - 8;
+        let charset_memory_offset = match inner.memory_mode {
+            MemoryMode::Reserved => mode_line.charset_memory_offset,
+            MemoryMode::AddressSpace => mode_line.charset_address(),
+        };
 
         push_positions(&mut inner.positions, mode_line);
         push_uvs(&mut inner.uvs);
-        push_custom(&mut inner.custom, mode_line);
+        push_custom(&mut inner.custom, mode_line, charset_memory_offset);
         push_indices(&mut inner.indices, index_offset);
+        inner.mode_lines.push(mode_line.clone());
+    }
+
+    /// Interprets display list from 64 KB `memory` image and inserts all its mode lines.
+    /// In `MemoryMode::Reserved` screen data and charsets referenced by display list
+    /// are copied to reserved antic memory.
+    pub fn insert_display_list(&mut self, memory: &[u8], regs: AnticRegs) {
+        if self.inner.read().memory_mode == MemoryMode::AddressSpace {
+            for mode_line in DisplayList::new(memory, regs) {
+                self.insert_mode_line(&mode_line);
+            }
+            return;
+        }
+        let mut charsets: HashMap<usize, usize> = HashMap::default();
+        // copy player / missile DMA area if enabled
+        let pmbase = if regs.dmactl & 0x0c > 0 {
+            let single_line = regs.dmactl & 0x10 > 0;
+            let (address, len) = if single_line {
+                ((regs.pmbase & 0xf8) as usize * 256, 2048)
+            } else {
+                ((regs.pmbase & 0xfc) as usize * 256, 1024)
+            };
+            self.reserve_pm_memory(single_line, &mut |data| {
+                data.copy_from_slice(&memory[address..address + len])
+            })
+        } else {
+            0
+        };
+        for mut mode_line in DisplayList::new(memory, regs) {
+            mode_line.pmbase = pmbase;
+            if mode_line.n_bytes > 0 {
+                let address = mode_line.data_offset;
+                mode_line.video_memory_offset =
+                    self.reserve_antic_memory(mode_line.n_bytes, &mut |data| {
+                        for (i, dst) in data.iter_mut().enumerate() {
+                            // memory scan counter wraps on 4K boundary
+                            *dst = memory[(address & 0xf000) | ((address + i) & 0xfff)];
+                        }
+                    });
+            }
+            let charset_size = mode_line.charset_size();
+            if charset_size > 0 {
+                let address = mode_line.charset_memory_offset;
+                mode_line.charset_memory_offset = *charsets.entry(address).or_insert_with(|| {
+                    self.reserve_antic_memory(charset_size, &mut |data| {
+                        data.copy_from_slice(&memory[address..address + charset_size])
+                    })
+                });
+            }
+            self.insert_mode_line(&mode_line);
+        }
+    }
+}
+
+/// Periodically toggles CHACTL `bits` of `AnticData` attached to the same entity,
+/// e.g. `bits: 2` blinks inverse video cursor.
+#[derive(Component)]
+pub struct ChactlBlink {
+    pub bits: u8,
+    pub period: f32,
+    pub elapsed: f32,
+}
+
+impl ChactlBlink {
+    pub fn new(bits: u8, period: f32) -> Self {
+        Self {
+            bits,
+            period,
+            elapsed: 0.0,
+        }
+    }
+}
+
+pub fn chactl_blink(
+    time: Res<Time>,
+    mut antic_data_assets: ResMut<Assets<AnticData>>,
+    mut query: Query<(&Handle<AnticData>, &mut ChactlBlink)>,
+) {
+    for (handle, mut blink) in query.iter_mut() {
+        blink.elapsed += time.delta_seconds();
+        if blink.elapsed < blink.period {
+            continue;
+        }
+        blink.elapsed -= blink.period;
+        if let Some(antic_data) = antic_data_assets.get_mut(handle) {
+            antic_data.config.chactl_xor ^= blink.bits as i32;
+        }
+    }
+}
+
+/// Applies video standard selected in `AtariAnticPlugin` to created `AnticData` assets,
+/// or to all of them when it is changed.
+pub fn apply_video_standard(
+    video_standard: Res<VideoStandard>,
+    mut events: EventReader<AssetEvent<AnticData>>,
+    mut antic_data_assets: ResMut<Assets<AnticData>>,
+) {
+    let created: Vec<HandleId> = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } => Some(handle.id),
+            _ => None,
+        })
+        .collect();
+    let ids = if video_standard.is_changed() {
+        antic_data_assets.ids().collect()
+    } else {
+        created
+    };
+    for id in ids {
+        let differs = matches!(
+            antic_data_assets.get(id),
+            Some(antic_data) if antic_data.video_standard() != *video_standard
+        );
+        if differs {
+            let antic_data = antic_data_assets.get_mut(id).unwrap();
+            antic_data.set_video_standard(*video_standard);
+        }
     }
 }
 
@@ -259,10 +699,11 @@ fn push_positions(positions: &mut Vec<[f32; 3]>, mode_line: &crate::ModeLineDesc
 
     // TODO - flip y using projection matrix, for some reason this didn't worked
 
-    let north_west = vec2(0.0, 240.0 - scan_line_y);
-    let north_east = vec2(384.0, 240.0 - scan_line_y);
-    let south_west = vec2(0.0, 240.0 - (scan_line_y + mode_line.height as f32));
-    let south_east = vec2(384.0, 240.0 - (scan_line_y + mode_line.height as f32));
+    let height = SCAN_LINES as f32;
+    let north_west = vec2(0.0, height - scan_line_y);
+    let north_east = vec2(384.0, height - scan_line_y);
+    let south_west = vec2(0.0, height - (scan_line_y + mode_line.height as f32));
+    let south_east = vec2(384.0, height - (scan_line_y + mode_line.height as f32));
 
     positions.push([south_west.x, south_west.y, 0.0]);
     positions.push([north_west.x, north_west.y, 0.0]);
@@ -270,7 +711,40 @@ fn push_positions(positions: &mut Vec<[f32; 3]>, mode_line: &crate::ModeLineDesc
     positions.push([south_east.x, south_east.y, 0.0]);
 }
 
-fn push_custom(custom: &mut Vec<[f32; 4]>, mode_line: &crate::ModeLineDescr) {
+/// Empty line following the last inserted mode line.
+pub(crate) fn paused_mode_line(scanlines: usize) -> Option<ModeLineDescr> {
+    let scan_line = scanlines + 8;
+    if scan_line < LAST_SCAN_LINE {
+        // hack for paused mode
+        // to display scan_line we need to add additional empty rect
+        // on the end of mesh with height = 1
+        // TODO: move this to some postprocessing pass
+        Some(ModeLineDescr {
+            mode: 0,
+            scan_line,
+            width: 384,
+            height: 1,
+            ..Default::default()
+        })
+    } else {
+        None
+    }
+}
+
+fn push_custom(
+    custom: &mut Vec<[f32; 4]>,
+    mode_line: &crate::ModeLineDescr,
+    charset_memory_offset: usize,
+) {
+    let value = mode_line_custom(mode_line, charset_memory_offset);
+    custom.extend([value; 4]);
+}
+
+/// Mode line parameters passed to the shader in "Vertex_ZCustom" attribute.
+pub(crate) fn mode_line_custom(
+    mode_line: &crate::ModeLineDescr,
+    charset_memory_offset: usize,
+) -> [f32; 4] {
     let scan_line_y = mode_line.scan_line as f32 - 8.0;
     let scan_line = scan_line_y as u32;
     let height = mode_line.height as u32;
@@ -279,13 +753,14 @@ fn push_custom(custom: &mut Vec<[f32; 4]>, mode_line: &crate::ModeLineDescr) {
     let b0 = (mode_line.mode as u32 | (scan_line << 8) | (height << 16)) as f32;
     let b1 =
         (mode_line.hscrol as u32 | ((mode_line.line_voffset as u32) << 8) | (width << 16)) as f32;
-    let b2 = mode_line.video_memory_offset as f32;
-    let b3 = mode_line.charset_memory_offset as f32;
-
-    custom.push([b0, b1, b2, b3]);
-    custom.push([b0, b1, b2, b3]);
-    custom.push([b0, b1, b2, b3]);
-    custom.push([b0, b1, b2, b3]);
+    assert!(mode_line.video_memory_offset < 0x10000);
+    let chactl = mode_line.chactl as u32 & 7;
+    let dmactl = (mode_line.dmactl as u32 >> 2) & 7;
+    let b2 = (mode_line.video_memory_offset as u32 | (chactl << 16) | (dmactl << 19)) as f32;
+    assert!(charset_memory_offset < 0x10000);
+    let b3 = (charset_memory_offset as u32 | ((mode_line.pmbase as u32) << 16)) as f32;
+
+    [b0, b1, b2, b3]
 }
 
 fn push_indices(indices: &mut Vec<u16>, index_offset: u16) {
@@ -308,3 +783,194 @@ fn push_uvs(uvs: &mut Vec<[f32; 2]>) {
     uvs.push([1.0, 0.0]);
     uvs.push([1.0, 1.0]);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::Poll,
+    };
+
+    use futures_lite::future;
+    use parking_lot::Mutex;
+
+    use super::{
+        AnticData, CollisionsData, CollisionsDataInner, MapFuture, MemoryMode, ReadbackBuffer,
+    };
+    use crate::{
+        display_list::SCAN_LINES,
+        software::{render, Frame},
+        AnticRegs, GTIARegs,
+    };
+
+    /// Mode 2 line with LMS 16 bytes before 4K boundary and a line with the same characters
+    /// stored without wrapping, charset at $e000.
+    fn memory() -> Vec<u8> {
+        let mut memory = vec![0; 0x10000];
+        let dlist = [0x42, 0xf0, 0x4f, 0x42, 0x00, 0x60, 0x41, 0x00, 0x20];
+        memory[0x2000..0x2000 + dlist.len()].copy_from_slice(&dlist);
+        for i in 0..40 {
+            let c = i as u8 + 1;
+            memory[0x4000 | ((0xff0 + i) & 0xfff)] = c;
+            memory[0x6000 + i] = c;
+        }
+        for (i, byte) in memory[0xe000..0xe400].iter_mut().enumerate() {
+            *byte = (i * 0x35 ^ i >> 3) as u8;
+        }
+        memory
+    }
+
+    fn render_display_list(memory_mode: MemoryMode) -> Frame {
+        let memory = memory();
+        let mut antic_data = AnticData::headless(memory_mode);
+        antic_data.config.debug_scan_line = -1;
+        let regs = GTIARegs {
+            col: [0, 0, 0, 0, 0x28, 0xca, 0x94, 0x46, 0],
+            ..Default::default()
+        };
+        for scan_line in 0..16 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        if memory_mode == MemoryMode::AddressSpace {
+            antic_data.set_atari_memory(0, &memory);
+        }
+        antic_data.insert_display_list(
+            &memory,
+            AnticRegs {
+                dmactl: 0x22,
+                chbase: 0xe0,
+                dlist: 0x2000,
+                ..Default::default()
+            },
+        );
+        let mut frame = Frame::default();
+        render(&antic_data, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn test_insert_display_list() {
+        for memory_mode in [MemoryMode::Reserved, MemoryMode::AddressSpace] {
+            let frame = render_display_list(memory_mode);
+            let line = |y: usize| &frame.pixels[y * 384 * 4..(y + 8) * 384 * 4];
+            // screen memory wraps on 4K boundary
+            assert!(line(0) == line(8), "{:?}", memory_mode);
+            // glyph row 0 of character 1 is read from charset selected by CHBASE
+            let glyph = memory()[0xe008];
+            let unlit = (0..8).find(|b| glyph & (0x80 >> b) == 0).unwrap();
+            for b in 0..8 {
+                let lit = frame.pixel(32 + b, 0) != frame.pixel(32 + unlit, 0);
+                assert_eq!(lit, glyph & (0x80 >> b) != 0, "{:?}", memory_mode);
+            }
+        }
+    }
+
+    /// Readback buffer with mapping finished when `done` is set.
+    struct FakeBuffer {
+        data: Mutex<Vec<u8>>,
+        done: Arc<AtomicBool>,
+    }
+
+    impl ReadbackBuffer for FakeBuffer {
+        fn map_read(&self) -> MapFuture {
+            let done = self.done.clone();
+            Box::pin(future::poll_fn(move |_| {
+                if done.load(Ordering::SeqCst) {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                }
+            }))
+        }
+
+        fn read_mapped(&self, f: &mut dyn FnMut(&[u8])) {
+            f(&self.data.lock())
+        }
+
+        fn unmap(&self) {}
+    }
+
+    /// Copies `value` as collisions of every scan line of the current frame, like `CollisionsAggReadNode`.
+    fn copy(inner: &mut CollisionsDataInner<FakeBuffer>, value: u64) {
+        if let Some(index) = inner.buffer_index {
+            let mut data = inner.buffers[index].data.lock();
+            data.fill(0);
+            for x in 0..SCAN_LINES {
+                data[x * 8..x * 8 + 8].copy_from_slice(&value.to_le_bytes());
+            }
+            drop(data);
+            CollisionsData::copied(inner, index);
+        }
+    }
+
+    #[test]
+    fn test_collisions_readback() {
+        let done = Arc::new(AtomicBool::new(false));
+        let size = (crate::COLLISIONS_AGG_TEXTURE_SIZE.width
+            * crate::COLLISIONS_AGG_TEXTURE_SIZE.height) as usize
+            * crate::COLLISIONS_AGG__BYTES_PER_PIXEL;
+        let buffers = (0..3)
+            .map(|_| FakeBuffer {
+                data: Mutex::new(vec![0; size]),
+                done: done.clone(),
+            })
+            .collect();
+        let mut inner = CollisionsDataInner::new(buffers);
+        inner.max_latency = Some(0);
+        // device finishes pending work only when waited for
+        let poll = |wait: bool| {
+            if wait {
+                done.store(true, Ordering::SeqCst);
+            }
+        };
+
+        // previous frame is always published
+        copy(&mut inner, 0x1);
+        inner.update(poll);
+        assert_eq!(inner.data_frame, Some(0));
+        assert_eq!(inner.registers[239], 0x1);
+
+        // HITCLR is taken from the frame copied, not from the frame extracted later
+        done.store(false, Ordering::SeqCst);
+        inner.pending_hitclr[100] = true;
+        copy(&mut inner, 0x2);
+        inner.pending_hitclr[100] = false;
+        inner.update(poll);
+        assert_eq!(inner.data_frame, Some(1));
+        assert_eq!(inner.registers[99], 0x3);
+        assert_eq!(inner.registers[100], 0x2);
+
+        // results may lag `max_latency` frames behind the previous one
+        done.store(false, Ordering::SeqCst);
+        inner.max_latency = Some(2);
+        for (frame, value) in [(3, 0x4), (4, 0x8)] {
+            copy(&mut inner, value);
+            inner.update(poll);
+            assert_eq!(inner.frame, frame);
+            assert_eq!(inner.data_frame, Some(1));
+        }
+        copy(&mut inner, 0x10);
+        inner.update(poll);
+        assert_eq!(inner.frame, 5);
+        assert_eq!(inner.data_frame, Some(4));
+        assert_eq!(inner.data[0], 0x10);
+
+        // without latency limit copies are skipped while all buffers are in flight
+        done.store(false, Ordering::SeqCst);
+        inner.max_latency = None;
+        for value in [0x20, 0x40, 0x80, 0x100] {
+            copy(&mut inner, value);
+            inner.update(poll);
+            assert_eq!(inner.data_frame, Some(4));
+        }
+        assert_eq!(inner.buffer_index, None);
+        done.store(true, Ordering::SeqCst);
+        inner.update(poll);
+        assert_eq!(inner.data_frame, Some(7));
+        assert_eq!(inner.data[0], 0x80);
+        assert!(inner.buffer_index.is_some());
+    }
+}
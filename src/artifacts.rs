@@ -0,0 +1,113 @@
+//! NTSC artifact colors of hires playfield (ANTIC modes 2, 3 and 15 with GTIA mode 0).
+//!
+//! A hires pixel is half of a color clock, so on NTSC sets patterns of lit pixels
+//! are decoded as color. Both modes are ported to `antic.wgsl`.
+
+/// Artifacting of hires playfield, stored in `AnticConfig::artifacts`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactMode {
+    #[default]
+    None = 0,
+    /// Color clocks with a single lit pixel get fixed artifact hue: orange for the left
+    /// pixel, blue for the right one, with luminance between COLPF2 and COLPF1.
+    Lookup = 1,
+    /// Luma of pixels is encoded into composite signal and decoded back, the difference
+    /// from pixel luma and chroma leaking from luma transitions are added to pixel color.
+    Signal = 2,
+}
+
+/// Hues of color clocks with the left and the right pixel lit in `ArtifactMode::Lookup`.
+pub(crate) const ARTIFACT_HUES: [i32; 2] = [0x20, 0x90];
+/// Phase of color subcarrier at the first sample of the main image, in degrees.
+pub(crate) const SUBCARRIER_PHASE: f32 = 90.0;
+
+/// `ArtifactMode::Lookup` color register of pixel at `x` of main image, `pixels` are hires
+/// pixel values at `x - 1`, `x` and `x + 1`. Returns `None` for color clocks without artifacts.
+pub(crate) fn lookup_color(x: i32, pixels: [i32; 3], colpf1: i32, colpf2: i32) -> Option<i32> {
+    // color clocks start at even pixels
+    let (left, right) = if x & 1 == 0 {
+        (pixels[1], pixels[2])
+    } else {
+        (pixels[0], pixels[1])
+    };
+    if left == right {
+        return None;
+    }
+    let lum = (((colpf1 & 0xf) + (colpf2 & 0xf)) / 2) & 0xe;
+    Some(ARTIFACT_HUES[right as usize] | lum)
+}
+
+/// `ArtifactMode::Signal` color of pixel at `x` of main image, `colors` are linear colors
+/// of pixels at `x - 1`, `x` and `x + 1`.
+///
+/// Every pixel is two samples of composite signal, four samples per subcarrier period.
+/// Luma and chroma are decoded from one period centered at the pixel.
+pub(crate) fn signal_color(x: i32, colors: [[f32; 4]; 3]) -> [f32; 4] {
+    let srgb = colors.map(|c| [c[0], c[1], c[2]].map(linear_to_srgb));
+    let luma = srgb.map(|[r, g, b]| 0.299 * r + 0.587 * g + 0.114 * b);
+    let phase = ((2 * x - 1) as f32 * 90.0 + SUBCARRIER_PHASE).to_radians();
+    let samples = [luma[0], luma[1], luma[1], luma[2]];
+    let (mut u, mut v) = (0.0, 0.0);
+    for (i, sample) in samples.iter().enumerate() {
+        let angle = phase + i as f32 * std::f32::consts::FRAC_PI_2;
+        u += 0.5 * sample * angle.cos();
+        v += 0.5 * sample * angle.sin();
+    }
+    let y = (luma[0] + 2.0 * luma[1] + luma[2]) / 4.0 - luma[1];
+    let delta = [y + 1.140 * v, y - 0.395 * u - 0.581 * v, y + 2.032 * u];
+    let mut color = [0.0, 0.0, 0.0, 1.0];
+    for ((c, srgb), delta) in color.iter_mut().zip(srgb[1]).zip(delta) {
+        *c = srgb_to_linear((srgb + delta).clamp(0.0, 1.0));
+    }
+    color
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_color, signal_color, srgb_to_linear};
+
+    #[test]
+    fn test_lookup() {
+        // left pixel of color clock lit: orange, right one: blue
+        assert_eq!(lookup_color(2, [0, 1, 0], 0x0e, 0x00), Some(0x26));
+        assert_eq!(lookup_color(3, [1, 0, 1], 0x0e, 0x00), Some(0x26));
+        assert_eq!(lookup_color(2, [0, 0, 1], 0x0e, 0x00), Some(0x96));
+        assert_eq!(lookup_color(2, [1, 1, 1], 0x0e, 0x00), None);
+    }
+
+    #[test]
+    fn test_signal() {
+        let black = [0.0, 0.0, 0.0, 1.0];
+        let white = [1.0, 1.0, 1.0, 1.0];
+        let grey = srgb_to_linear(0.5);
+        let grey = [grey, grey, grey, 1.0];
+        // uniform areas keep their color
+        for c in [black, white, grey] {
+            let color = signal_color(5, [c; 3]);
+            for (c, e) in color.iter().zip(c) {
+                assert!((c - e).abs() < 1e-4, "{:?}", color);
+            }
+        }
+        // alternating pixels: even lit is orange, odd lit is blue
+        let [r, g, b, _] = signal_color(4, [black, white, black]);
+        assert!(r > g && g > b);
+        let [r, g, b, _] = signal_color(5, [black, white, black]);
+        assert!(b > g && g > r);
+    }
+}
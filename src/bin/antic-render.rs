@@ -0,0 +1,428 @@
+//! Renders a frame snapshot, or a 64 KB memory dump with register file, to PNG
+//! or to the terminal, using the software renderer (no window or GPU needed).
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    process::exit,
+};
+
+use bevy_atari_antic::{
+    software::{self, Frame, FRAME_HEIGHT, FRAME_WIDTH},
+    terminal::{self, TerminalFormat, TerminalOptions},
+    AnticData, AnticRegs, ArtifactMode, AtariPalette, Collisions, DisplayList, GTIARegs,
+    MemoryMode, VideoStandard,
+};
+
+const USAGE: &str = "\
+usage: antic-render [options] (--snapshot FILE | --memory FILE --regs FILE)
+                    (-o OUTPUT.png | --terminal FORMAT)
+
+inputs:
+    --snapshot FILE      frame snapshot saved with AnticData::save_snapshot
+    --memory FILE        raw 64 KB memory dump
+    --regs FILE          register file for --memory, lines of `NAME VALUE`, e.g. `DLIST $9c20`
+                         (ANTIC: DMACTL CHACTL CHBASE HSCROL VSCROL PMBASE DLIST,
+                         GTIA: HPOSP0-3 HPOSM0-3 SIZEP0-3 SIZEM GRAFP0-3 GRAFM COLPM0-3
+                         COLPF0-3 COLBK PRIOR VDELAY GRACTL)
+
+options:
+    -o, --output FILE    output PNG file
+    --palette FILE       palette file (.pal, .act)
+    --standard STANDARD  video standard of --memory dump: pal (default) or ntsc
+    --artifacts MODE     NTSC artifacts of hires modes: none, lookup or signal
+    --pal-blending       emulate PAL delay line, blending chroma of adjacent scan lines
+    --overscan AREA      full (384x240, default), wide (352x224) or normal (320x192)
+    --crop X,Y,W,H       crop rectangle, overrides --overscan
+    --scale N            integer scale factor, 1 to 16 (default 1)
+    --collisions FILE    write collisions detected on every scan line, `-` for stdout
+    --terminal FORMAT    print frame to the terminal: halfblocks, sixel or auto
+    --columns N          width of terminal output in characters / pixels (default 192)
+";
+
+/// Largest `--scale`, 16x scaled frame is 6144x3840 pixels.
+const MAX_SCALE: usize = 16;
+
+struct Options {
+    snapshot: Option<PathBuf>,
+    memory: Option<PathBuf>,
+    regs: Option<PathBuf>,
+    output: Option<PathBuf>,
+    palette: Option<PathBuf>,
+    standard: VideoStandard,
+    artifacts: Option<ArtifactMode>,
+    pal_blending: bool,
+    crop: (usize, usize, usize, usize),
+    scale: usize,
+    collisions: Option<String>,
+    terminal: Option<TerminalFormat>,
+    columns: usize,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        snapshot: None,
+        memory: None,
+        regs: None,
+        output: None,
+        palette: None,
+        standard: VideoStandard::Pal,
+        artifacts: None,
+        pal_blending: false,
+        crop: (0, 0, FRAME_WIDTH, FRAME_HEIGHT),
+        scale: 1,
+        collisions: None,
+        terminal: None,
+        columns: 192,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value of {}", arg));
+        match arg.as_str() {
+            "--snapshot" => options.snapshot = Some(value()?.into()),
+            "--memory" => options.memory = Some(value()?.into()),
+            "--regs" => options.regs = Some(value()?.into()),
+            "-o" | "--output" => options.output = Some(value()?.into()),
+            "--palette" => options.palette = Some(value()?.into()),
+            "--standard" => {
+                options.standard = match value()?.as_str() {
+                    "pal" => VideoStandard::Pal,
+                    "ntsc" => VideoStandard::Ntsc,
+                    standard => return Err(format!("unknown video standard {}", standard)),
+                }
+            }
+            "--artifacts" => {
+                options.artifacts = Some(match value()?.as_str() {
+                    "none" => ArtifactMode::None,
+                    "lookup" => ArtifactMode::Lookup,
+                    "signal" => ArtifactMode::Signal,
+                    mode => return Err(format!("unknown artifact mode {}", mode)),
+                })
+            }
+            "--pal-blending" => options.pal_blending = true,
+            "--overscan" => {
+                options.crop = match value()?.as_str() {
+                    "full" => (0, 0, FRAME_WIDTH, FRAME_HEIGHT),
+                    "wide" => (16, 8, 352, 224),
+                    "normal" => (32, 24, 320, 192),
+                    area => return Err(format!("unknown overscan area {}", area)),
+                }
+            }
+            "--crop" => {
+                let value = value()?;
+                let parts = value
+                    .split(',')
+                    .map(|v| v.trim().parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| format!("invalid crop rectangle {}", value))?;
+                options.crop = match parts[..] {
+                    [x, y, w, h]
+                        if w > 0
+                            && h > 0
+                            && matches!(x.checked_add(w), Some(right) if right <= FRAME_WIDTH)
+                            && matches!(y.checked_add(h), Some(bottom) if bottom <= FRAME_HEIGHT) =>
+                    {
+                        (x, y, w, h)
+                    }
+                    _ => return Err(format!("invalid crop rectangle {}", value)),
+                }
+            }
+            "--scale" => {
+                options.scale = match value()?.parse() {
+                    Ok(scale) if (1..=MAX_SCALE).contains(&scale) => scale,
+                    _ => return Err(format!("scale must be an integer from 1 to {}", MAX_SCALE)),
+                }
+            }
+            "--collisions" => options.collisions = Some(value()?),
+            "--terminal" => {
+                options.terminal = Some(match value()?.as_str() {
+                    "halfblocks" => TerminalFormat::HalfBlocks,
+                    "sixel" => TerminalFormat::Sixel,
+                    "auto" => TerminalFormat::detect(),
+                    format => return Err(format!("unknown terminal format {}", format)),
+                })
+            }
+            "--columns" => {
+                options.columns = match value()?.parse() {
+                    Ok(columns) if columns > 0 => columns,
+                    _ => return Err("columns must be a positive integer".to_string()),
+                }
+            }
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    if options.output.is_none() && options.terminal.is_none() {
+        return Err("missing output file or terminal format".to_string());
+    }
+    match (&options.snapshot, &options.memory, &options.regs) {
+        (Some(_), None, None) | (None, Some(_), Some(_)) => Ok(options),
+        _ => Err("either --snapshot or both --memory and --regs are required".to_string()),
+    }
+}
+
+fn parse_value(value: &str) -> Option<u16> {
+    if let Some(hex) = value.strip_prefix('$').or_else(|| value.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Parses register file: `NAME VALUE` (or `NAME=VALUE`) lines, `#` starts a comment.
+fn parse_regs(text: &str) -> Result<(AnticRegs, GTIARegs), String> {
+    let mut antic = AnticRegs::default();
+    let mut gtia = GTIARegs::default();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split(|c: char| c == '=' || c.is_whitespace());
+        let name = parts.next().unwrap().to_ascii_uppercase();
+        let value = parts
+            .find(|v| !v.is_empty())
+            .and_then(parse_value)
+            .ok_or(format!("line {}: invalid value", line_no + 1))?;
+        if name == "DLIST" {
+            antic.dlist = value;
+            continue;
+        }
+        let byte = u8::try_from(value).map_err(|_| {
+            format!(
+                "line {}: value {} does not fit in a byte",
+                line_no + 1,
+                value
+            )
+        })?;
+        let index = |prefix: &str| {
+            name.strip_prefix(prefix)
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|&n| n < 4)
+        };
+        match name.as_str() {
+            "DMACTL" => antic.dmactl = byte,
+            "CHACTL" => antic.chactl = byte,
+            "CHBASE" => antic.chbase = byte,
+            "HSCROL" => antic.hscrol = byte,
+            "VSCROL" => antic.vscrol = byte,
+            "PMBASE" => antic.pmbase = byte,
+            "SIZEM" => gtia.sizem = byte,
+            "GRAFM" => gtia.grafm = byte,
+            "COLBK" => gtia.col[8] = byte,
+            "PRIOR" => gtia.prior = byte,
+            "VDELAY" => gtia.vdelay = byte,
+            "GRACTL" => gtia.gractl = byte,
+            _ => {
+                if let Some(n) = index("HPOSP") {
+                    gtia.hposp[n] = byte;
+                } else if let Some(n) = index("HPOSM") {
+                    gtia.hposm[n] = byte;
+                } else if let Some(n) = index("SIZEP") {
+                    gtia.sizep[n] = byte;
+                } else if let Some(n) = index("GRAFP") {
+                    gtia.grafp[n] = byte;
+                } else if let Some(n) = index("COLPM") {
+                    gtia.col[n] = byte;
+                } else if let Some(n) = index("COLPF") {
+                    gtia.col[4 + n] = byte;
+                } else {
+                    return Err(format!("line {}: unknown register {}", line_no + 1, name));
+                }
+            }
+        }
+    }
+    Ok((antic, gtia))
+}
+
+fn load(options: &Options) -> Result<AnticData, Box<dyn Error>> {
+    let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+    if let Some(path) = &options.snapshot {
+        antic_data.load_snapshot(BufReader::new(File::open(path)?))?;
+    } else if let (Some(memory), Some(regs)) = (&options.memory, &options.regs) {
+        let memory = std::fs::read(memory)?;
+        if memory.len() != 0x10000 {
+            return Err(format!("memory dump has {} bytes, expected 65536", memory.len()).into());
+        }
+        let (antic_regs, gtia_regs) = parse_regs(&std::fs::read_to_string(regs)?)?;
+        antic_data.config.debug_scan_line = -1;
+        antic_data.set_video_standard(options.standard);
+        antic_data.set_atari_memory(0, &memory);
+        for scan_line in 0..FRAME_HEIGHT {
+            antic_data.set_gtia_regs(scan_line, &gtia_regs);
+        }
+        for mode_line in DisplayList::new(&memory, antic_regs) {
+            antic_data.insert_mode_line(&mode_line);
+        }
+    }
+    if let Some(path) = &options.palette {
+        antic_data.set_palette(AtariPalette::from_bytes(&std::fs::read(path)?)?);
+    }
+    if let Some(artifacts) = options.artifacts {
+        antic_data.config.artifacts = artifacts as i32;
+    }
+    if options.pal_blending {
+        antic_data.config.pal_blending = 1;
+    }
+    Ok(antic_data)
+}
+
+fn write_image(options: &Options, frame: &Frame) -> Result<(), Box<dyn Error>> {
+    let (x0, y0, width, height) = options.crop;
+    let scale = options.scale;
+    let mut pixels = Vec::with_capacity(width * height * scale * scale * 4);
+    for y in y0..y0 + height {
+        let mut row = Vec::with_capacity(width * scale * 4);
+        for x in x0..x0 + width {
+            let pixel = frame.pixel(x, y);
+            for _ in 0..scale {
+                row.extend(pixel);
+            }
+        }
+        for _ in 0..scale {
+            pixels.extend(&row);
+        }
+    }
+    let path = options.output.as_ref().unwrap();
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, (width * scale) as u32, (height * scale) as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+/// Writes collision registers `$D000-$D00F` detected on every scan line with any collision,
+/// followed by values aggregated over the whole frame.
+fn write_collisions(mut writer: impl Write, frame: &Frame) -> std::io::Result<()> {
+    let format = |collisions: Collisions| {
+        collisions
+            .registers()
+            .iter()
+            .map(|reg| format!("{:x}", reg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    writeln!(
+        writer,
+        "# line  M0PF..M3PF P0PF..P3PF M0PL..M3PL P0PL..P3PL"
+    )?;
+    for (scan_line, &value) in frame.collisions.iter().enumerate() {
+        if value != 0 {
+            writeln!(writer, "{:5}   {}", scan_line, format(Collisions(value)))?;
+        }
+    }
+    let total = frame.collisions.iter().map(|&v| Collisions(v)).collect();
+    writeln!(writer, "frame   {}", format(total))
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    let antic_data = load(options)?;
+    let mut frame = Frame::default();
+    software::render(&antic_data, &mut frame);
+    if options.output.is_some() {
+        write_image(options, &frame)?;
+    }
+    if let Some(format) = options.terminal {
+        let terminal_options = TerminalOptions {
+            crop: options.crop,
+            scale: options.columns as f32 / options.crop.2 as f32,
+        };
+        let output = match format {
+            TerminalFormat::HalfBlocks => terminal::half_blocks(&frame, &terminal_options),
+            TerminalFormat::Sixel => terminal::sixel(&frame, &terminal_options),
+        };
+        std::io::stdout().lock().write_all(output.as_bytes())?;
+    }
+    match options.collisions.as_deref() {
+        Some("-") => write_collisions(std::io::stdout().lock(), &frame)?,
+        Some(path) => write_collisions(BufWriter::new(File::create(path)?), &frame)?,
+        None => (),
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("antic-render: {}\n\n{}", err, USAGE);
+        exit(2);
+    });
+    if let Err(err) = run(&options) {
+        eprintln!("antic-render: {}", err);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, parse_regs, parse_value, Options};
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args = ["--snapshot", "frame.snp", "-o", "frame.png"]
+            .iter()
+            .chain(args);
+        parse_args(args.map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("$9c20"), Some(0x9c20));
+        assert_eq!(parse_value("0x0E"), Some(0x0e));
+        assert_eq!(parse_value("42"), Some(42));
+        assert_eq!(parse_value("$10000"), None);
+        assert_eq!(parse_value("x"), None);
+    }
+
+    #[test]
+    fn test_parse_regs() {
+        let text = "DLIST $9c20\n# comment\ncolpf1=$0e\nHPOSP3 100 # right\n\nCOLBK 0x94\n";
+        let (antic, gtia) = parse_regs(text).unwrap();
+        assert_eq!(antic.dlist, 0x9c20);
+        assert_eq!(gtia.col[5], 0x0e);
+        assert_eq!(gtia.hposp[3], 100);
+        assert_eq!(gtia.col[8], 0x94);
+
+        assert!(parse_regs("COLPF0 256").is_err());
+        assert!(parse_regs("HPOSP4 0").is_err());
+        assert!(parse_regs("NMIEN $40").is_err());
+        assert!(parse_regs("DMACTL").is_err());
+    }
+
+    #[test]
+    fn test_crop() {
+        assert_eq!(parse(&["--crop", "1,2,3,4"]).unwrap().crop, (1, 2, 3, 4));
+        assert_eq!(
+            parse(&["--crop", "0, 0, 384, 240"]).unwrap().crop,
+            (0, 0, 384, 240)
+        );
+        assert!(parse(&["--crop", "0,0,385,1"]).is_err());
+        assert!(parse(&["--crop", "0,0,0,1"]).is_err());
+        assert!(parse(&["--crop", "1,2,3"]).is_err());
+        assert!(parse(&["--crop", "18446744073709551615,0,1,1"]).is_err());
+        assert!(parse(&["--crop", "0,18446744073709551615,1,1"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().crop, (0, 0, 384, 240));
+        assert_eq!(
+            parse(&["--overscan", "wide"]).unwrap().crop,
+            (16, 8, 352, 224)
+        );
+        assert_eq!(
+            parse(&["--overscan", "normal"]).unwrap().crop,
+            (32, 24, 320, 192)
+        );
+        assert!(parse(&["--overscan", "narrow"]).is_err());
+        assert!(parse(&["--overscan"]).is_err());
+    }
+
+    #[test]
+    fn test_scale() {
+        assert_eq!(parse(&["--scale", "16"]).unwrap().scale, 16);
+        assert!(parse(&["--scale", "0"]).is_err());
+        assert!(parse(&["--scale", "100000"]).is_err());
+    }
+}
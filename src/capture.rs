@@ -0,0 +1,360 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    ecs::prelude::*,
+    prelude::{Assets, Handle},
+    render::renderer::RenderDevice,
+};
+
+use crate::{
+    resources::AtariPalette,
+    software::{FRAME_HEIGHT, FRAME_WIDTH},
+    AnticData, VideoStandard,
+};
+
+fn encoding_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Writes RGBA `pixels` of a single frame (as returned by `ImageReadback::read`) as PNG.
+pub fn write_png(writer: impl Write, pixels: &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, FRAME_WIDTH as u32, FRAME_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    writer.write_image_data(pixels).map_err(encoding_error)
+}
+
+/// Sequence of captured frames, encoded as animated GIF or APNG.
+///
+/// Frames are stored as indices into Atari palette, so both formats are lossless.
+/// Colors not present in the palette (like `debug_scan_line` tint) are mapped to the nearest entry.
+pub struct FrameRecorder {
+    palette: [[u8; 3]; 256],
+    lookup: HashMap<[u8; 3], u8>,
+    frames: Vec<Vec<u8>>,
+    /// frames per second
+    pub fps: f32,
+}
+
+impl FrameRecorder {
+    pub fn new(palette: &AtariPalette, fps: f32) -> Self {
+        let palette = palette.to_srgb();
+        let mut lookup = HashMap::new();
+        for (index, color) in palette.iter().enumerate() {
+            lookup.entry(*color).or_insert(index as u8);
+        }
+        Self {
+            palette,
+            lookup,
+            frames: Vec::new(),
+            fps,
+        }
+    }
+
+    /// Adds frame of RGBA `pixels`.
+    pub fn push(&mut self, pixels: &[u8]) {
+        let palette = &self.palette;
+        let frame = pixels
+            .chunks(4)
+            .map(|c| {
+                let color = [c[0], c[1], c[2]];
+                *self
+                    .lookup
+                    .entry(color)
+                    .or_insert_with(|| nearest_color(palette, color))
+            })
+            .collect();
+        self.frames.push(frame);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    fn palette_bytes(&self) -> Vec<u8> {
+        self.palette.iter().flatten().cloned().collect()
+    }
+
+    /// Writes frames as looped GIF. GIF delays have 1/100 s resolution, so delays
+    /// of consecutive frames differ when needed to keep the frame rate (2, 1, 2 for 60 fps).
+    pub fn write_gif(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = gif::Encoder::new(
+            writer,
+            FRAME_WIDTH as u16,
+            FRAME_HEIGHT as u16,
+            &self.palette_bytes(),
+        )
+        .map_err(encoding_error)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(encoding_error)?;
+        let delays = gif_delays(self.fps, self.frames.len());
+        for (frame, delay) in self.frames.iter().zip(delays) {
+            let frame = gif::Frame {
+                width: FRAME_WIDTH as u16,
+                height: FRAME_HEIGHT as u16,
+                delay,
+                buffer: frame.as_slice().into(),
+                ..Default::default()
+            };
+            encoder.write_frame(&frame).map_err(encoding_error)?;
+        }
+        Ok(())
+    }
+
+    /// Writes frames as looped APNG with indexed colors.
+    pub fn write_apng(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, FRAME_WIDTH as u32, FRAME_HEIGHT as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.palette_bytes());
+        encoder
+            .set_animated(self.frames.len() as u32, 0)
+            .map_err(encoding_error)?;
+        let (delay_num, delay_den) = apng_delay(self.fps);
+        encoder
+            .set_frame_delay(delay_num, delay_den)
+            .map_err(encoding_error)?;
+        let mut writer = encoder.write_header().map_err(encoding_error)?;
+        for frame in &self.frames {
+            writer.write_image_data(frame).map_err(encoding_error)?;
+        }
+        writer.finish().map_err(encoding_error)
+    }
+
+    /// Writes frames to `path`, as GIF if its extension is `gif`, as APNG otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => self.write_gif(writer),
+            _ => self.write_apng(writer),
+        }
+    }
+}
+
+/// GIF delays of `count` frames in 1/100 s, with the rounding error carried to the next frame.
+fn gif_delays(fps: f32, count: usize) -> Vec<u16> {
+    let mut start = 0;
+    (1..=count)
+        .map(|n| {
+            let end = (n as f64 * 100.0 / fps as f64).round() as u64;
+            let delay = end.saturating_sub(start).clamp(1, u16::MAX as u64);
+            start += delay;
+            delay as u16
+        })
+        .collect()
+}
+
+/// APNG frame delay as `numerator / denominator` seconds, both fitting in `u16`.
+fn apng_delay(fps: f32) -> (u16, u16) {
+    let scale = [1000.0, 100.0, 10.0, 1.0]
+        .into_iter()
+        .find(|scale| fps * scale <= u16::MAX as f32)
+        .unwrap_or(1.0);
+    let denominator = (fps * scale).round().clamp(1.0, u16::MAX as f32);
+    (scale as u16, denominator as u16)
+}
+
+fn nearest_color(palette: &[[u8; 3]; 256], color: [u8; 3]) -> u8 {
+    let distance = |c: &[u8; 3]| -> i32 {
+        (0..3)
+            .map(|i| (c[i] as i32 - color[i] as i32).pow(2))
+            .sum()
+    };
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, c)| distance(c))
+        .map_or(0, |(index, _)| index as u8)
+}
+
+enum CaptureRequest {
+    Screenshot(PathBuf),
+    Record { path: PathBuf, frames: usize },
+}
+
+struct ActiveCapture {
+    path: PathBuf,
+    remaining: usize,
+    recorder: Option<FrameRecorder>,
+}
+
+/// Captures main image of `AnticData` of the same entity to files.
+/// Requires image readback enabled with `AnticData::enable_image_readback`.
+#[derive(Component, Default)]
+pub struct AnticCapture {
+    /// frame rate of recordings, `None` for frame rate of the video standard of `AnticData`
+    pub fps: Option<f32>,
+    requests: VecDeque<CaptureRequest>,
+    active: Option<ActiveCapture>,
+    waiting: bool,
+}
+
+impl AnticCapture {
+    /// Saves the next rendered frame as PNG.
+    pub fn screenshot(&mut self, path: impl Into<PathBuf>) {
+        self.requests
+            .push_back(CaptureRequest::Screenshot(path.into()));
+    }
+
+    /// Records `frames` consecutive frames to GIF or APNG file, depending on `path` extension.
+    /// At least one frame is recorded.
+    pub fn record(&mut self, path: impl Into<PathBuf>, frames: usize) {
+        self.requests.push_back(CaptureRequest::Record {
+            path: path.into(),
+            frames: frames.max(1),
+        });
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.active.is_some() || !self.requests.is_empty()
+    }
+
+    fn start(&mut self, palette: &AtariPalette, video_standard: VideoStandard) {
+        let fps = self
+            .fps
+            .unwrap_or_else(|| video_standard.frame_rate() as f32);
+        self.active = self.requests.pop_front().map(|request| match request {
+            CaptureRequest::Screenshot(path) => ActiveCapture {
+                path,
+                remaining: 1,
+                recorder: None,
+            },
+            CaptureRequest::Record { path, frames } => ActiveCapture {
+                path,
+                remaining: frames,
+                recorder: Some(FrameRecorder::new(palette, fps)),
+            },
+        });
+    }
+
+    fn add_frame(&mut self, pixels: &[u8]) -> io::Result<()> {
+        let active = match self.active.as_mut() {
+            Some(active) => active,
+            None => return Ok(()),
+        };
+        active.remaining = active.remaining.saturating_sub(1);
+        if let Some(recorder) = active.recorder.as_mut() {
+            recorder.push(pixels);
+        }
+        if active.remaining > 0 {
+            return Ok(());
+        }
+        let active = self.active.take().unwrap();
+        match active.recorder {
+            Some(recorder) => recorder.save(&active.path),
+            None => write_png(BufWriter::new(File::create(&active.path)?), pixels),
+        }
+    }
+}
+
+pub fn antic_capture(
+    render_device: Res<RenderDevice>,
+    antic_data_assets: Res<Assets<AnticData>>,
+    mut query: Query<(&Handle<AnticData>, &mut AnticCapture)>,
+) {
+    for (handle, mut capture) in query.iter_mut() {
+        if !capture.is_busy() {
+            continue;
+        }
+        let antic_data = match antic_data_assets.get(handle) {
+            Some(antic_data) => antic_data,
+            None => continue,
+        };
+        let readback = match antic_data.image_readback.as_ref() {
+            Some(readback) => readback,
+            None => {
+                bevy::log::warn!("AnticCapture requires AnticData::enable_image_readback");
+                continue;
+            }
+        };
+        if capture.waiting {
+            if let Some(pixels) = readback.read(&render_device) {
+                capture.waiting = false;
+                if let Err(err) = capture.add_frame(&pixels) {
+                    bevy::log::error!("capture failed: {}", err);
+                }
+            }
+        }
+        if capture.active.is_none() {
+            let inner = antic_data.inner.read();
+            capture.start(&inner.palette, inner.video_standard);
+        }
+        if capture.active.is_some() && !capture.waiting {
+            readback.request();
+            capture.waiting = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apng_delay, gif_delays, AnticCapture, FrameRecorder};
+    use crate::{
+        resources::AtariPalette,
+        software::{FRAME_HEIGHT, FRAME_WIDTH},
+    };
+
+    #[test]
+    fn test_gif_is_lossless() {
+        let palette = AtariPalette::default();
+        let srgb = palette.to_srgb();
+        let mut recorder = FrameRecorder::new(&palette, 50.0);
+        let frames: Vec<Vec<u8>> = (0..2)
+            .map(|n| {
+                (0..FRAME_WIDTH * FRAME_HEIGHT)
+                    .flat_map(|i| {
+                        let [r, g, b] = srgb[(i * 7 + n) & 0xff];
+                        [r, g, b, 255]
+                    })
+                    .collect()
+            })
+            .collect();
+        for frame in &frames {
+            recorder.push(frame);
+        }
+        let mut gif = Vec::new();
+        recorder.write_gif(&mut gif).unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(gif.as_slice()).unwrap();
+        for frame in &frames {
+            let decoded = decoder.read_next_frame().unwrap().unwrap();
+            assert_eq!(decoded.delay, 2);
+            assert_eq!(decoded.buffer.as_ref(), frame.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_frame_delays() {
+        assert_eq!(gif_delays(50.0, 3), [2, 2, 2]);
+        assert_eq!(gif_delays(60.0, 6), [2, 1, 2, 2, 1, 2]);
+        // 100 frames at 59.92 fps take 1.67 s
+        assert_eq!(gif_delays(59.92, 100).iter().sum::<u16>(), 167);
+        assert_eq!(gif_delays(200.0, 2), [1, 1]);
+
+        assert_eq!(apng_delay(50.0), (1000, 50000));
+        assert_eq!(apng_delay(59.92), (1000, 59920));
+        assert_eq!(apng_delay(70.0), (100, 7000));
+        assert_eq!(apng_delay(1e6), (1, 65535));
+    }
+
+    #[test]
+    fn test_record_empty() {
+        let mut capture = AnticCapture::default();
+        capture.record("empty.png", 0);
+        capture.start(&AtariPalette::default(), Default::default());
+        assert_eq!(capture.active.as_ref().unwrap().remaining, 1);
+    }
+}
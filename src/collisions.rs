@@ -0,0 +1,150 @@
+use std::ops::BitOr;
+
+/// GTIA collision registers of a single scan line or aggregated over range of scan lines.
+///
+/// Value is packed the same way as data produced by the shader:
+/// nibble `n` holds register `$D000 + n`, so the order is M0PF..M3PF, P0PF..P3PF,
+/// M0PL..M3PL, P0PL..P3PL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Collisions(pub u64);
+
+impl Collisions {
+    pub const M0PF: usize = 0x0;
+    pub const P0PF: usize = 0x4;
+    pub const M0PL: usize = 0x8;
+    pub const P0PL: usize = 0xc;
+
+    /// Value of collision register `$D000 + index`.
+    pub fn register(&self, index: usize) -> u8 {
+        assert!(index < 16);
+        ((self.0 >> (index * 4)) & 0xf) as u8
+    }
+
+    /// All 16 collision registers, starting from M0PF.
+    pub fn registers(&self) -> [u8; 16] {
+        let mut regs = [0; 16];
+        for (index, reg) in regs.iter_mut().enumerate() {
+            *reg = self.register(index);
+        }
+        regs
+    }
+
+    /// MxPF register: playfields hit by `missile`.
+    pub fn missile_playfield(&self, missile: usize) -> u8 {
+        self.register(Self::M0PF + missile)
+    }
+
+    /// PxPF register: playfields hit by `player`.
+    pub fn player_playfield(&self, player: usize) -> u8 {
+        self.register(Self::P0PF + player)
+    }
+
+    /// MxPL register: players hit by `missile`.
+    pub fn missile_player(&self, missile: usize) -> u8 {
+        self.register(Self::M0PL + missile)
+    }
+
+    /// PxPL register: other players hit by `player`.
+    pub fn player_player(&self, player: usize) -> u8 {
+        self.register(Self::P0PL + player)
+    }
+
+    pub fn missile_hit_playfield(&self, missile: usize, playfield: usize) -> bool {
+        self.missile_playfield(missile) & (1 << playfield) > 0
+    }
+
+    pub fn player_hit_playfield(&self, player: usize, playfield: usize) -> bool {
+        self.player_playfield(player) & (1 << playfield) > 0
+    }
+
+    pub fn missile_hit_player(&self, missile: usize, player: usize) -> bool {
+        self.missile_player(missile) & (1 << player) > 0
+    }
+
+    pub fn player_hit_player(&self, player: usize, other: usize) -> bool {
+        self.player_player(player) & (1 << other) > 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Collisions {
+    type Output = Collisions;
+
+    fn bitor(self, rhs: Collisions) -> Collisions {
+        Collisions(self.0 | rhs.0)
+    }
+}
+
+impl FromIterator<Collisions> for Collisions {
+    fn from_iter<T: IntoIterator<Item = Collisions>>(iter: T) -> Self {
+        iter.into_iter().fold(Collisions::default(), BitOr::bitor)
+    }
+}
+
+/// Horizontal region in which collisions are detected, set with `AnticConfig::collisions_region`.
+#[repr(i32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionsRegion {
+    /// Playfield of the mode line, as selected by DMACTL.
+    Playfield = 0,
+    /// Whole visible line, from the end of horizontal blank (color clock $22) to its start ($DE),
+    /// where GTIA detects collisions regardless of playfield width.
+    #[default]
+    Visible = 1,
+    /// Whole 384 pixel wide line, including parts covered by horizontal blank.
+    Full = 2,
+}
+
+/// Computes values of collision registers as seen at the end of every scan line.
+///
+/// Registers keep accumulating collisions detected on consecutive `lines`, starting from
+/// `prev` (state left by previous frame). They are cleared before scan line `n` is drawn
+/// if HITCLR was written at that line (`hitclr[n]` is set).
+pub fn accumulate(prev: Collisions, lines: &[u64], hitclr: &[bool], registers: &mut [u64]) {
+    let mut acc = prev.0;
+    for (n, line) in lines.iter().enumerate() {
+        if hitclr[n] {
+            acc = 0;
+        }
+        acc |= line;
+        registers[n] = acc;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accumulate, Collisions};
+
+    #[test]
+    fn test_registers() {
+        // P1PF = 4 (playfield 2), M3PL = 1 (player 0)
+        let collisions = Collisions((0x4 << 20) | (0x1 << 44));
+        assert!(collisions.player_hit_playfield(1, 2));
+        assert!(!collisions.player_hit_playfield(1, 1));
+        assert!(collisions.missile_hit_player(3, 0));
+        let mut expected = [0; 16];
+        expected[Collisions::P0PF + 1] = 4;
+        expected[Collisions::M0PL + 3] = 1;
+        assert_eq!(collisions.registers(), expected);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let lines = [Collisions(0x1), Collisions(0x20), Collisions(0)];
+        let collisions: Collisions = lines.iter().cloned().collect();
+        assert_eq!(collisions, Collisions(0x21));
+    }
+
+    #[test]
+    fn test_hitclr() {
+        let lines = [0x1, 0x2, 0x4, 0x8];
+        let mut registers = [0; 4];
+        accumulate(Collisions(0x10), &lines, &[false; 4], &mut registers);
+        assert_eq!(registers, [0x11, 0x13, 0x17, 0x1f]);
+        accumulate(Collisions(0x10), &lines, &[false, false, true, false], &mut registers);
+        assert_eq!(registers, [0x11, 0x13, 0x4, 0xc]);
+    }
+}
@@ -0,0 +1,316 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ModeLineDescr;
+
+/// First scan line fetched by ANTIC after vertical blank.
+pub const FIRST_SCAN_LINE: usize = 8;
+/// Scan line at which ANTIC stops processing the display list.
+pub const LAST_SCAN_LINE: usize = 248;
+/// Number of scan lines displayed by ANTIC, height of the main image.
+pub const SCAN_LINES: usize = LAST_SCAN_LINE - FIRST_SCAN_LINE;
+
+/// Set when the wide playfield HSCROL warning was logged.
+static WIDE_HSCROL_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// ANTIC registers used by the display list interpreter.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AnticRegs {
+    pub dmactl: u8,
+    pub chactl: u8,
+    pub chbase: u8,
+    pub hscrol: u8,
+    pub vscrol: u8,
+    pub pmbase: u8,
+    pub dlist: u16,
+}
+
+impl AnticRegs {
+    /// Playfield width in hires pixels, as selected by DMACTL bits 0-1.
+    pub fn playfield_width(&self) -> usize {
+        match self.dmactl & 3 {
+            1 => 256,
+            2 => 320,
+            3 => 384,
+            _ => 0,
+        }
+    }
+}
+
+/// Number of scan lines of a single mode line.
+pub fn mode_height(mode: u8) -> usize {
+    match mode {
+        2 | 4 | 6 | 8 => 8,
+        3 => 10,
+        5 | 7 => 16,
+        9 | 10 => 4,
+        11 | 13 => 2,
+        _ => 1,
+    }
+}
+
+/// Width (in hires pixels) of data fetched from single byte of video memory.
+pub fn pixels_per_byte(mode: u8) -> usize {
+    match mode {
+        2..=5 | 13..=15 => 8,
+        6 | 7 | 10..=12 => 16,
+        8 | 9 => 32,
+        _ => 0,
+    }
+}
+
+/// Walks ANTIC display list stored in 64 KB memory image and yields
+/// mode lines ready to be inserted with `AnticData::insert_mode_line`.
+///
+/// Produced descriptors use Atari addresses: `video_memory_offset` and `data_offset`
+/// point to screen data of the line and `charset_memory_offset` to the charset selected by CHBASE.
+/// `hscrol` is the pixel offset into fetched line data, as expected by the shader.
+///
+/// Horizontal scrolling of wide playfield is not supported: ANTIC shifts the whole line right
+/// by HSCROL color clocks, which can't be expressed with the offset, so such lines are fetched
+/// and displayed as if HSCROL was 0. A warning is logged the first time it happens.
+///
+/// `regs` may be modified between lines to emulate register changes done in DLIs.
+pub struct DisplayList<'a> {
+    memory: &'a [u8],
+    pub regs: AnticRegs,
+    pc: u16,
+    memory_scan: u16,
+    scan_line: usize,
+    prev_vscrol: bool,
+}
+
+impl<'a> DisplayList<'a> {
+    pub fn new(memory: &'a [u8], regs: AnticRegs) -> Self {
+        assert!(memory.len() >= 0x10000);
+        Self {
+            memory,
+            regs,
+            pc: regs.dlist,
+            memory_scan: 0,
+            scan_line: FIRST_SCAN_LINE,
+            prev_vscrol: false,
+        }
+    }
+
+    fn read_dlist_byte(&mut self) -> u8 {
+        let value = self.memory[self.pc as usize];
+        // display list counter wraps on 1K boundary
+        self.pc = (self.pc & 0xfc00) | (self.pc.wrapping_add(1) & 0x3ff);
+        value
+    }
+
+    fn read_dlist_word(&mut self) -> u16 {
+        let lo = self.read_dlist_byte() as u16;
+        let hi = self.read_dlist_byte() as u16;
+        lo | (hi << 8)
+    }
+
+    fn blank_line(&mut self, height: usize, dli: bool) -> ModeLineDescr {
+        let height = height.min(LAST_SCAN_LINE - self.scan_line);
+        let mode_line = ModeLineDescr {
+            mode: 0,
+            scan_line: self.scan_line,
+            width: self.regs.playfield_width(),
+            height,
+            chbase: self.regs.chbase,
+            pmbase: self.regs.pmbase,
+            dmactl: self.regs.dmactl,
+            dli,
+            ..Default::default()
+        };
+        self.scan_line += height;
+        self.prev_vscrol = false;
+        mode_line
+    }
+}
+
+impl<'a> Iterator for DisplayList<'a> {
+    type Item = ModeLineDescr;
+
+    fn next(&mut self) -> Option<ModeLineDescr> {
+        let width = self.regs.playfield_width();
+        if self.scan_line >= LAST_SCAN_LINE || width == 0 || self.regs.dmactl & 0x20 == 0 {
+            return None;
+        }
+        let ir = self.read_dlist_byte();
+        let mode = ir & 0xf;
+        let dli = ir & 0x80 > 0;
+
+        match mode {
+            0 => Some(self.blank_line((((ir >> 4) & 7) + 1) as usize, dli)),
+            1 => {
+                let addr = self.read_dlist_word();
+                self.pc = addr;
+                if ir & 0x40 > 0 {
+                    // JVB - nothing more is displayed until vertical blank
+                    Some(self.blank_line(LAST_SCAN_LINE, dli))
+                } else {
+                    Some(self.blank_line(1, dli))
+                }
+            }
+            _ => {
+                if ir & 0x40 > 0 {
+                    self.memory_scan = self.read_dlist_word();
+                }
+                let vscrol = ir & 0x20 > 0;
+                let hscrol = ir & 0x10 > 0;
+                let mode_height = mode_height(mode);
+
+                let first_row = if vscrol && !self.prev_vscrol {
+                    self.regs.vscrol as usize & 0xf
+                } else {
+                    0
+                };
+                let last_row = if !vscrol && self.prev_vscrol {
+                    self.regs.vscrol as usize & 0xf
+                } else {
+                    mode_height - 1
+                };
+                self.prev_vscrol = vscrol;
+                let height = ((last_row.wrapping_sub(first_row)) & 0xf) + 1;
+                let height = height.min(LAST_SCAN_LINE - self.scan_line);
+
+                let (fetch_width, hscrol) = if hscrol {
+                    let fetch_width = (width + 64).min(384);
+                    let offset = (fetch_width - width) / 2;
+                    if offset == 0
+                        && self.regs.hscrol & 0xf > 0
+                        && !WIDE_HSCROL_WARNED.swap(true, Ordering::Relaxed)
+                    {
+                        bevy::log::warn!("HSCROL of wide playfield is not supported, ignoring it");
+                    }
+                    (
+                        fetch_width,
+                        offset.saturating_sub(self.regs.hscrol as usize * 2),
+                    )
+                } else {
+                    (width, 0)
+                };
+                let n_bytes = fetch_width / pixels_per_byte(mode);
+
+                let mut mode_line = ModeLineDescr {
+                    mode,
+                    scan_line: self.scan_line,
+                    width,
+                    height,
+                    n_bytes,
+                    line_voffset: first_row,
+                    data_offset: self.memory_scan as usize,
+                    chbase: self.regs.chbase,
+                    pmbase: self.regs.pmbase,
+                    hscrol: hscrol as u8,
+                    video_memory_offset: self.memory_scan as usize,
+                    chactl: self.regs.chactl,
+                    dmactl: self.regs.dmactl,
+                    dli,
+                    ..Default::default()
+                };
+                mode_line.charset_memory_offset = mode_line.charset_address();
+                // memory scan counter wraps on 4K boundary
+                self.memory_scan = (self.memory_scan & 0xf000)
+                    | (self.memory_scan.wrapping_add(n_bytes as u16) & 0xfff);
+                self.scan_line += height;
+                Some(mode_line)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_with_dlist(dlist: &[u8]) -> Vec<u8> {
+        let mut memory = vec![0; 0x10000];
+        memory[0x1000..0x1000 + dlist.len()].copy_from_slice(dlist);
+        memory
+    }
+
+    fn interpret(memory: &[u8], regs: AnticRegs) -> Vec<ModeLineDescr> {
+        DisplayList::new(memory, regs).collect()
+    }
+
+    fn regs() -> AnticRegs {
+        AnticRegs {
+            dmactl: 0x22,
+            chbase: 0xe0,
+            dlist: 0x1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_gr0_display_list() {
+        let mut dlist = vec![0x70, 0x70, 0x70, 0x42, 0x40, 0x9c];
+        dlist.extend([0x02; 23]);
+        dlist.extend([0x41, 0x00, 0x10]);
+        let memory = memory_with_dlist(&dlist);
+        let mode_lines = interpret(&memory, regs());
+
+        assert_eq!(mode_lines.len(), 3 + 24 + 1);
+        let text_lines = &mode_lines[3..27];
+        assert_eq!(text_lines[0].scan_line, 32);
+        for (i, line) in text_lines.iter().enumerate() {
+            assert_eq!(line.mode, 2);
+            assert_eq!(line.height, 8);
+            assert_eq!(line.n_bytes, 40);
+            assert_eq!(line.width, 320);
+            assert_eq!(line.video_memory_offset, 0x9c40 + i * 40);
+            assert_eq!(line.charset_memory_offset, 0xe000);
+        }
+        let jvb = &mode_lines[27];
+        assert_eq!(jvb.mode, 0);
+        assert_eq!(jvb.scan_line, 224);
+        assert_eq!(jvb.next_mode_line(), LAST_SCAN_LINE);
+    }
+
+    #[test]
+    fn test_vscrol() {
+        let memory = memory_with_dlist(&[0x62, 0x00, 0x20, 0x22, 0x02, 0x41, 0x00, 0x10]);
+        let mode_lines = interpret(
+            &memory,
+            AnticRegs {
+                vscrol: 3,
+                ..regs()
+            },
+        );
+        let heights: Vec<_> = mode_lines
+            .iter()
+            .map(|l| (l.line_voffset, l.height))
+            .collect();
+        assert_eq!(&heights[..3], &[(3, 5), (0, 8), (0, 4)]);
+    }
+
+    #[test]
+    fn test_hscrol() {
+        let memory = memory_with_dlist(&[0x52, 0x00, 0x20, 0x41, 0x00, 0x10]);
+        let mode_lines = interpret(
+            &memory,
+            AnticRegs {
+                hscrol: 4,
+                ..regs()
+            },
+        );
+        assert_eq!(mode_lines[0].n_bytes, 48);
+        assert_eq!(mode_lines[0].hscrol, 24);
+        assert_eq!(mode_lines[0].data_offset, 0x2000);
+    }
+
+    #[test]
+    fn test_wide_hscrol() {
+        // limitation: HSCROL of wide playfield is ignored with a warning
+        let memory = memory_with_dlist(&[0x52, 0x00, 0x20, 0x41, 0x00, 0x10]);
+        let mode_lines = interpret(
+            &memory,
+            AnticRegs {
+                dmactl: 0x23,
+                hscrol: 4,
+                ..regs()
+            },
+        );
+        assert_eq!(mode_lines[0].width, 384);
+        assert_eq!(mode_lines[0].n_bytes, 48);
+        assert_eq!(mode_lines[0].hscrol, 0);
+        assert!(WIDE_HSCROL_WARNED.load(Ordering::Relaxed));
+    }
+}
@@ -1,5 +1,5 @@
 use bevy::{
-    prelude::{AddAsset, App, Assets, Handle, HandleUntyped, Plugin},
+    prelude::{AddAsset, App, Assets, CoreStage, Handle, HandleUntyped, Plugin},
     reflect::TypeUuid,
     render::{
         render_asset::RenderAssetPlugin,
@@ -15,21 +15,57 @@ use bevy::{
 pub use wgpu;
 
 mod antic_data;
+mod artifacts;
+#[cfg(feature = "capture")]
+mod capture;
+mod collisions;
+mod display_list;
+mod pal_blending;
+mod palette;
 mod resources;
+mod readback;
+mod recording;
 mod render;
-use render::pass::{AnticPassNode, AnticPhase, CollisionsAggPhase};
+mod rewind;
+mod snapshot;
+pub mod software;
+pub mod terminal;
+mod video_standard;
+use render::pass::{AnticPassNode, AnticPhase, CollisionsAggPhase, PalBlendingPhase};
 
 const ANTIC_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 9390220767195311254);
 
 // Public Interface
 
-pub use antic_data::{AnticData, CollisionsData};
+pub use antic_data::{AnticData, ChactlBlink, CollisionsData, MemoryMode};
+pub use artifacts::ArtifactMode;
+#[cfg(feature = "capture")]
+pub use capture::{write_png, AnticCapture, FrameRecorder};
+pub use collisions::{Collisions, CollisionsRegion};
+pub use display_list::{AnticRegs, DisplayList, SCAN_LINES};
+pub use palette::{AtariPaletteLoader, PaletteGenerator};
+#[cfg(feature = "image")]
+pub use readback::rgba_image;
+pub use readback::{render_offscreen, ImageReadback, OffscreenRenderer};
+pub use recording::{AnticReplay, Recorder, Recording, RECORDING_VERSION};
+pub use resources::AtariPalette;
+pub use rewind::RewindBuffer;
+pub use snapshot::{Snapshot, SNAPSHOT_VERSION};
+pub use video_standard::VideoStandard;
 
-use crate::render::pass::{CollisionsAggNode, CollisionsAggReadNode};
+use crate::render::pass::{
+    CollisionsAggNode, CollisionsAggReadNode, MainImageReadNode, PalBlendingNode,
+};
 
+#[derive(Default)]
 pub struct AtariAnticPlugin {
     pub collisions: bool,
+    /// Number of recent frames kept in `RewindBuffer` inserted to every `AnticData` entity,
+    /// 0 disables rewind.
+    pub rewind_frames: usize,
+    /// Video standard applied to every `AnticData`, available as a resource.
+    pub video_standard: VideoStandard,
 }
 
 const COLLISIONS_AGG_TEXTURE_SIZE: Extent3d = Extent3d {
@@ -43,16 +79,17 @@ pub fn create_main_image(images: &mut Assets<Image>) -> Handle<Image> {
     let mut image = Image::new(
         Extent3d {
             width: 384,
-            height: 240,
+            height: SCAN_LINES as u32,
             depth_or_array_layers: 1,
         },
         wgpu::TextureDimension::D2,
-        vec![128; 384 * 240 * 4],
+        vec![128; 384 * SCAN_LINES * 4],
         wgpu::TextureFormat::Rgba8UnormSrgb,
     );
     image.texture_descriptor.usage = wgpu::TextureUsages::TEXTURE_BINDING
         | wgpu::TextureUsages::RENDER_ATTACHMENT
-        | wgpu::TextureUsages::COPY_DST;
+        | wgpu::TextureUsages::COPY_DST
+        | wgpu::TextureUsages::COPY_SRC;
     images.add(image)
 }
 
@@ -63,9 +100,22 @@ impl Plugin for AtariAnticPlugin {
         shaders.set_untracked(ANTIC_SHADER_HANDLE, antic_shader);
 
         app.add_asset::<AnticData>()
+            .add_asset::<AtariPalette>()
+            .init_asset_loader::<AtariPaletteLoader>()
             // .add_asset::<AnticMesh>()
             .add_plugin(ExtractComponentPlugin::<Handle<AnticData>>::default())
-            .add_plugin(RenderAssetPlugin::<AnticData>::default());
+            .add_plugin(RenderAssetPlugin::<AnticData>::default())
+            .add_system(antic_data::chactl_blink)
+            .add_system(recording::antic_replay)
+            .insert_resource(self.video_standard)
+            .add_system(antic_data::apply_video_standard)
+            .add_system(palette::apply_palette);
+        #[cfg(feature = "capture")]
+        app.add_system(capture::antic_capture);
+        if self.rewind_frames > 0 {
+            app.insert_resource(rewind::RewindFrames(self.rewind_frames))
+                .add_system_to_stage(CoreStage::PostUpdate, rewind::antic_rewind);
+        }
 
         let render_app = app.sub_app_mut(RenderApp);
         render_app
@@ -73,13 +123,18 @@ impl Plugin for AtariAnticPlugin {
             .init_resource::<RenderPhase<AnticPhase>>()
             .init_resource::<DrawFunctions<CollisionsAggPhase>>()
             .init_resource::<RenderPhase<CollisionsAggPhase>>()
+            .init_resource::<DrawFunctions<PalBlendingPhase>>()
+            .init_resource::<RenderPhase<PalBlendingPhase>>()
             .init_resource::<render::AnticPipeline>()
             .init_resource::<render::CollisionsAggPipeline>()
+            .init_resource::<render::PalBlendingPipeline>()
             .init_resource::<HashMap<Handle<Image>, render::GpuAnticData>>()
             .init_resource::<SpecializedPipelines<render::AnticPipeline>>()
             .init_resource::<SpecializedPipelines<render::CollisionsAggPipeline>>()
+            .init_resource::<SpecializedPipelines<render::PalBlendingPipeline>>()
             .add_render_command::<AnticPhase, render::SetAnticPipeline>()
             .add_render_command::<CollisionsAggPhase, render::SetCollisionsAggPipeline>()
+            .add_render_command::<PalBlendingPhase, render::SetPalBlendingPipeline>()
             .add_system_to_stage(RenderStage::Queue, render::queue_meshes);
 
         let antic_node = AnticPassNode::default();
@@ -93,6 +148,28 @@ impl Plugin for AtariAnticPlugin {
             )
             .unwrap();
 
+        graph.add_node("pal_blending_node", PalBlendingNode);
+        graph
+            .add_node_edge("antic_node", "pal_blending_node")
+            .unwrap();
+        graph
+            .add_node_edge(
+                "pal_blending_node",
+                bevy::core_pipeline::node::MAIN_PASS_DEPENDENCIES,
+            )
+            .unwrap();
+
+        graph.add_node("main_image_read_node", MainImageReadNode);
+        graph
+            .add_node_edge("pal_blending_node", "main_image_read_node")
+            .unwrap();
+        graph
+            .add_node_edge(
+                "main_image_read_node",
+                bevy::core_pipeline::node::MAIN_PASS_DEPENDENCIES,
+            )
+            .unwrap();
+
         if self.collisions {
             graph.add_node("collisions_agg_node", CollisionsAggNode::default());
 
@@ -120,7 +197,7 @@ impl Plugin for AtariAnticPlugin {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct ModeLineDescr {
     pub mode: u8,
     pub scan_line: usize,
@@ -130,10 +207,41 @@ pub struct ModeLineDescr {
     pub line_voffset: usize,
     pub data_offset: usize,
     pub chbase: u8,
+    /// PMBASE register, selects player / missile DMA area used when enabled in DMACTL and GRACTL.
+    /// In `MemoryMode::Reserved` it should be obtained from `AnticData::reserve_pm_memory`.
     pub pmbase: u8,
     pub hscrol: u8,
     pub video_memory_offset: usize,
     pub charset_memory_offset: usize,
+    pub dli: bool,
+    /// CHACTL register: bit 0 - blank, bit 1 - inverse video, bit 2 - vertical reflect.
+    /// Blank and inverse affect modes 2 and 3, reflect affects all character modes.
+    pub chactl: u8,
+    /// DMACTL register, bit 4 selects single-line player / missile resolution.
+    pub dmactl: u8,
+}
+
+impl Default for ModeLineDescr {
+    fn default() -> Self {
+        Self {
+            mode: 0,
+            scan_line: 0,
+            width: 0,
+            height: 0,
+            n_bytes: 0,
+            line_voffset: 0,
+            data_offset: 0,
+            chbase: 0,
+            pmbase: 0,
+            hscrol: 0,
+            video_memory_offset: 0,
+            charset_memory_offset: 0,
+            dli: false,
+            // inverse video enabled, as set by OS
+            chactl: 2,
+            dmactl: 0x22,
+        }
+    }
 }
 
 impl ModeLineDescr {
@@ -147,6 +255,42 @@ impl ModeLineDescr {
             _ => 0,
         }
     }
+    /// Glyph row displayed on `row` of the mode line (counted including `line_voffset`)
+    /// for character `c`, `None` if the row is displayed blank.
+    pub fn glyph_row(&self, row: usize, c: u8) -> Option<usize> {
+        glyph_row(self.mode, self.chactl, row, c)
+    }
+    /// Atari address of the charset selected by `chbase`.
+    pub fn charset_address(&self) -> usize {
+        match self.mode {
+            2..=5 => (self.chbase & 0xfc) as usize * 256,
+            6..=7 => (self.chbase & 0xfe) as usize * 256,
+            _ => 0,
+        }
+    }
+}
+
+/// Glyph row displayed on `row` of character mode line, shared with software renderer.
+pub(crate) fn glyph_row(mode: u8, chactl: u8, row: usize, c: u8) -> Option<usize> {
+    let glyph_row = match mode {
+        3 => {
+            // characters $60-$7F have descenders: top two rows are displayed at the bottom
+            let descender = c & 0x60 == 0x60;
+            match row {
+                0..=1 if descender => None,
+                0..=7 => Some(row),
+                8..=9 if descender => Some(row - 8),
+                _ => None,
+            }
+        }
+        5 | 7 => Some(row / 2),
+        _ => Some(row),
+    };
+    if chactl & 4 > 0 {
+        glyph_row.map(|row| row ^ 7)
+    } else {
+        glyph_row
+    }
 }
 
 #[repr(C)]
@@ -162,15 +306,77 @@ pub struct GTIARegs {
     pub prior: u8,
     pub vdelay: u8,
     pub gractl: u8,
+    /// non-zero value clears collision registers before this scan line is drawn
     pub hitclr: u8,
     pub consol: u8,
 }
 
 #[cfg(test)]
 mod tests {
+    use super::ModeLineDescr;
+
     #[test]
     fn test_vec() {
         let v: Vec<u8> = Vec::with_capacity(16);
         assert!(v.capacity() == 16);
     }
+
+    #[test]
+    fn test_mode3_glyph_rows() {
+        let mode_line = ModeLineDescr {
+            mode: 3,
+            height: 10,
+            ..Default::default()
+        };
+        let rows = |c| (0..10).map(|y| mode_line.glyph_row(y, c)).collect::<Vec<_>>();
+        let regular = rows(0x21);
+        assert_eq!(&regular[..8], &(0..8).map(Some).collect::<Vec<_>>()[..]);
+        assert_eq!(&regular[8..], &[None, None]);
+
+        for c in [0x60, 0x7f, 0xe7] {
+            let descender = rows(c);
+            assert_eq!(&descender[..2], &[None, None]);
+            assert_eq!(&descender[2..8], &(2..8).map(Some).collect::<Vec<_>>()[..]);
+            assert_eq!(&descender[8..], &[Some(0), Some(1)]);
+        }
+    }
+
+    #[test]
+    fn test_mode5_glyph_rows() {
+        let mode_line = ModeLineDescr {
+            mode: 5,
+            height: 16,
+            ..Default::default()
+        };
+        let rows = (0..16)
+            .map(|y| mode_line.glyph_row(y, 0x41).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7]);
+
+        // first line of vertically scrolled region starts at VSCROL row
+        let scrolled = ModeLineDescr {
+            mode: 5,
+            height: 13,
+            line_voffset: 3,
+            ..Default::default()
+        };
+        let rows = (0..scrolled.height)
+            .map(|cy| scrolled.glyph_row(cy + scrolled.line_voffset, 0x41).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7]);
+    }
+
+    #[test]
+    fn test_chactl_reflect() {
+        let mode_line = ModeLineDescr {
+            mode: 2,
+            height: 8,
+            chactl: 4,
+            ..Default::default()
+        };
+        let rows = (0..8)
+            .map(|y| mode_line.glyph_row(y, 0x41).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![7, 6, 5, 4, 3, 2, 1, 0]);
+    }
 }
@@ -0,0 +1,51 @@
+//! PAL delay line emulation, enabled with `AnticConfig::pal_blending`.
+//!
+//! PAL decoders average chroma of the current and the previous scan line, so programs
+//! alternate colors on adjacent lines to display colors missing from the palette.
+//! `blend` is ported to `pal_blending_fragment` in `antic.wgsl`.
+
+/// Color of pixel `current` (sRGB) with chroma averaged with pixel `previous`
+/// of the previous scan line. Luma of `current` is kept.
+pub(crate) fn blend(current: [f32; 3], previous: [f32; 3]) -> [f32; 3] {
+    let [cu, cv] = chroma(current);
+    let [pu, pv] = chroma(previous);
+    let (du, dv) = ((pu - cu) / 2.0, (pv - cv) / 2.0);
+    let delta = [1.140 * dv, -0.395 * du - 0.581 * dv, 2.032 * du];
+    let mut color = current;
+    for (c, delta) in color.iter_mut().zip(delta) {
+        *c = (*c + delta).clamp(0.0, 1.0);
+    }
+    color
+}
+
+/// U and V components of sRGB `color`.
+fn chroma([r, g, b]: [f32; 3]) -> [f32; 2] {
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    [0.492 * (b - y), 0.877 * (r - y)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blend, chroma};
+
+    #[test]
+    fn test_blend() {
+        let red = [1.0, 0.0, 0.0];
+        let blue = [0.0, 0.0, 1.0];
+        let grey = [0.5, 0.5, 0.5];
+        assert_eq!(blend(red, red), red);
+        assert_eq!(blend(grey, grey), grey);
+
+        // chroma is halved on a line following grey one, luma is kept
+        let luma = |[r, g, b]: [f32; 3]| 0.299 * r + 0.587 * g + 0.114 * b;
+        let color = blend(grey, red);
+        assert!((luma(color) - luma(grey)).abs() < 1e-3);
+        let [u, v] = chroma(color);
+        let [ru, rv] = chroma(red);
+        assert!((u - ru / 2.0).abs() < 1e-2 && (v - rv / 2.0).abs() < 1e-2);
+
+        // alternating red and blue lines mix to purple
+        let [r, g, b] = blend(blue, red);
+        assert!(r > g && b > g);
+    }
+}
@@ -0,0 +1,156 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    ecs::prelude::*,
+    prelude::{Assets, Handle},
+    utils::BoxedFuture,
+};
+
+use crate::{AnticData, AtariPalette, VideoStandard};
+
+/// Computes 256 GTIA colors from a YUV color model.
+///
+/// Color `hue * 16 + lum` has luma `brightness + contrast * lum / 15`. Hue 0 is grey,
+/// hues 1-15 have chroma of `saturation` amplitude at angle `hue_start - (hue - 1) * hue_step`
+/// degrees in the UV plane. The result is clamped and raised to `1 / gamma`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteGenerator {
+    /// Angle of hue 1, in degrees.
+    pub hue_start: f64,
+    /// Angle between consecutive hues, in degrees.
+    pub hue_step: f64,
+    pub saturation: f64,
+    /// Luma of luminance 0.
+    pub brightness: f64,
+    /// Luma difference between luminance 15 and 0.
+    pub contrast: f64,
+    pub gamma: f64,
+}
+
+impl Default for PaletteGenerator {
+    fn default() -> Self {
+        Self::for_standard(VideoStandard::Pal)
+    }
+}
+
+impl PaletteGenerator {
+    /// Preset of `standard`. The default NTSC palette is generated from the NTSC preset,
+    /// the PAL one approximates the default (Altirra) PAL palette, with hue 15 equal to hue 1.
+    pub fn for_standard(standard: VideoStandard) -> Self {
+        let (hue_start, hue_step) = match standard {
+            VideoStandard::Pal => (135.0, 360.0 / 14.0),
+            VideoStandard::Ntsc => (147.0, 24.0),
+        };
+        Self {
+            hue_start,
+            hue_step,
+            saturation: 0.3,
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+        }
+    }
+
+    /// Colors as 256 sRGB triplets, in `.pal` file format.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(256 * 3);
+        for index in 0..256 {
+            let (hue, lum) = (index >> 4, index & 15);
+            let y = self.brightness + self.contrast * lum as f64 / 15.0;
+            let (u, v) = if hue == 0 {
+                (0.0, 0.0)
+            } else {
+                let angle = (self.hue_start - (hue - 1) as f64 * self.hue_step).to_radians();
+                (self.saturation * angle.cos(), self.saturation * angle.sin())
+            };
+            let rgb = [y + 1.140 * v, y - 0.395 * u - 0.581 * v, y + 2.032 * u];
+            for c in rgb {
+                let c = c.clamp(0.0, 1.0).powf(1.0 / self.gamma);
+                data.push((c * 255.0 + 0.5).floor() as u8);
+            }
+        }
+        data
+    }
+
+    pub fn generate(&self) -> AtariPalette {
+        AtariPalette::from_rgb(&self.to_rgb()).unwrap()
+    }
+}
+
+/// Loads `AtariPalette` assets from `.pal` and `.act` files, see `AtariPalette::from_bytes`.
+#[derive(Default)]
+pub struct AtariPaletteLoader;
+
+impl AssetLoader for AtariPaletteLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let palette = AtariPalette::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(palette));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["pal", "act"]
+    }
+}
+
+/// Applies `Handle<AtariPalette>` attached to an `AnticData` entity once the palette
+/// is loaded, and again whenever the handle or the palette asset changes.
+pub(crate) fn apply_palette(
+    palettes: Res<Assets<AtariPalette>>,
+    mut antic_data_assets: ResMut<Assets<AnticData>>,
+    query: Query<(&Handle<AnticData>, &Handle<AtariPalette>)>,
+) {
+    for (handle, palette_handle) in query.iter() {
+        let palette = match palettes.get(palette_handle) {
+            Some(palette) => *palette,
+            None => continue,
+        };
+        let differs = matches!(
+            antic_data_assets.get(handle),
+            Some(antic_data) if antic_data.palette() != palette
+        );
+        if differs {
+            antic_data_assets.get_mut(handle).unwrap().set_palette(palette);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PaletteGenerator;
+    use crate::VideoStandard;
+
+    #[test]
+    fn test_generator() {
+        let ntsc = PaletteGenerator::for_standard(VideoStandard::Ntsc);
+        let pal = PaletteGenerator::for_standard(VideoStandard::Pal).to_rgb();
+        assert_eq!(pal[15 * 48..15 * 48 + 48], pal[48..96]);
+        // greys from black to white
+        assert_eq!(pal[..3], [0, 0, 0]);
+        assert_eq!(pal[45..48], [255, 255, 255]);
+
+        let dark = PaletteGenerator {
+            gamma: 0.5,
+            ..ntsc
+        };
+        let (ntsc, dark) = (ntsc.to_rgb(), dark.to_rgb());
+        assert!(dark.iter().zip(&ntsc).all(|(d, n)| d <= n));
+        assert!(dark[21] < ntsc[21]);
+    }
+
+    #[test]
+    fn test_pal_preset() {
+        // hues of Altirra palette are not evenly spaced, greens differ the most
+        let pal = PaletteGenerator::for_standard(VideoStandard::Pal).to_rgb();
+        let altirra = include_bytes!("altirra.pal");
+        let diff = |(a, b): (&u8, &u8)| (*a as i32 - *b as i32).abs();
+        assert!(pal.iter().zip(altirra.iter()).map(diff).all(|d| d <= 56));
+        let total: i32 = pal.iter().zip(altirra.iter()).map(diff).sum();
+        assert!(total < 8 * 768);
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use bevy::{
+    asset::AssetPlugin,
+    core::CorePlugin,
+    core_pipeline::CorePipelinePlugin,
+    prelude::{App, Assets, Handle},
+    render::{render_resource::Buffer, renderer::RenderDevice, texture::Image, RenderPlugin},
+    window::WindowPlugin,
+};
+use futures_lite::future;
+use parking_lot::RwLock;
+use wgpu::{BufferDescriptor, BufferUsages};
+
+use crate::{
+    software::{FRAME_HEIGHT, FRAME_WIDTH},
+    AnticData, AtariAnticPlugin, MemoryMode,
+};
+
+/// Bytes per row of main image, 384 * 4 is a multiple of `COPY_BYTES_PER_ROW_ALIGNMENT`.
+pub const MAIN_IMAGE_BYTES_PER_ROW: usize = FRAME_WIDTH * 4;
+
+/// Number of frames rendered before offscreen frame is captured,
+/// needed for pipelines to be compiled and assets prepared.
+const WARMUP_FRAMES: usize = 2;
+
+pub struct ImageReadbackInner {
+    pub buffer: Buffer,
+    /// copy of the main image requested for the next rendered frame
+    pub requested: bool,
+    /// main image was copied to `buffer`
+    pub copied: bool,
+}
+
+/// Copies main image of `AnticData` to CPU memory on request.
+///
+/// Call `request` to copy the main image rendered in the next frame, then `read` to get its
+/// RGBA pixels (`FRAME_WIDTH` x `FRAME_HEIGHT`, sRGB).
+#[derive(Clone)]
+pub struct ImageReadback {
+    pub inner: Arc<RwLock<ImageReadbackInner>>,
+}
+
+impl ImageReadback {
+    pub fn new(render_device: &RenderDevice) -> Self {
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("atari main image readback buffer"),
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            size: (MAIN_IMAGE_BYTES_PER_ROW * FRAME_HEIGHT) as u64,
+            mapped_at_creation: false,
+        });
+        Self {
+            inner: Arc::new(RwLock::new(ImageReadbackInner {
+                buffer,
+                requested: false,
+                copied: false,
+            })),
+        }
+    }
+
+    /// Requests copy of the main image rendered in the next frame.
+    pub fn request(&self) {
+        self.inner.write().requested = true;
+    }
+
+    /// Returns pixels of the main image copied after `request`, waiting for GPU if needed.
+    /// Returns `None` if requested frame was not rendered yet.
+    pub fn read(&self, render_device: &RenderDevice) -> Option<Vec<u8>> {
+        let mut inner = self.inner.write();
+        if !inner.copied {
+            return None;
+        }
+        inner.copied = false;
+        let slice = inner.buffer.slice(..);
+        let map_future = slice.map_async(wgpu::MapMode::Read);
+        render_device.poll(wgpu::Maintain::Wait);
+        future::block_on(map_future).ok()?;
+        let pixels = slice.get_mapped_range().to_vec();
+        inner.buffer.unmap();
+        Some(pixels)
+    }
+}
+
+/// Converts pixels returned by `ImageReadback::read` or `software::render` to an image.
+#[cfg(feature = "image")]
+pub fn rgba_image(pixels: Vec<u8>) -> Option<image::RgbaImage> {
+    image::RgbaImage::from_raw(FRAME_WIDTH as u32, FRAME_HEIGHT as u32, pixels)
+}
+
+/// Windowless Bevy app rendering a single `AnticData` on GPU.
+pub struct OffscreenRenderer {
+    app: App,
+    render_device: RenderDevice,
+    handle: Handle<AnticData>,
+    readback: ImageReadback,
+}
+
+impl OffscreenRenderer {
+    pub fn new(memory_mode: MemoryMode) -> Self {
+        Self::create(memory_mode, false)
+    }
+
+    /// Renderer with collisions detection enabled, see `CollisionsData`.
+    pub fn with_collisions(memory_mode: MemoryMode) -> Self {
+        Self::create(memory_mode, true)
+    }
+
+    fn create(memory_mode: MemoryMode, collisions: bool) -> Self {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .add_plugin(WindowPlugin {
+                add_primary_window: false,
+                exit_on_close: false,
+            })
+            .add_plugin(AssetPlugin)
+            .add_plugin(RenderPlugin)
+            .add_plugin(CorePipelinePlugin)
+            .add_plugin(AtariAnticPlugin {
+                collisions,
+                ..Default::default()
+            });
+
+        let render_device = app.world.get_resource::<RenderDevice>().unwrap().clone();
+        let mut images = app.world.get_resource_mut::<Assets<Image>>().unwrap();
+        let main_image_handle = crate::create_main_image(&mut images);
+        let mut antic_data =
+            AnticData::with_memory_mode(&render_device, main_image_handle, collisions, memory_mode);
+        antic_data.enable_image_readback(&render_device);
+        let readback = antic_data.image_readback.clone().unwrap();
+
+        let mut antic_data_assets = app.world.get_resource_mut::<Assets<AnticData>>().unwrap();
+        let handle = antic_data_assets.add(antic_data);
+        app.world.spawn().insert(handle.clone());
+        Self {
+            app,
+            render_device,
+            handle,
+            readback,
+        }
+    }
+
+    /// Rendered data, changes are uploaded to GPU with the next `render`.
+    pub fn antic_data_mut(&mut self) -> &mut AnticData {
+        self.app
+            .world
+            .get_resource_mut::<Assets<AnticData>>()
+            .unwrap()
+            .into_inner()
+            .get_mut(&self.handle)
+            .unwrap()
+    }
+
+    /// Renders a single frame and reads back collisions of already rendered frames.
+    pub fn update(&mut self) {
+        self.app.update();
+        if let Some(collisions_data) = self.antic_data_mut().collisions_data.clone() {
+            collisions_data.read_collisions(&self.render_device);
+        }
+    }
+
+    /// Renders a few frames and returns RGBA pixels of the main image.
+    pub fn render(&mut self) -> Vec<u8> {
+        for _ in 0..WARMUP_FRAMES {
+            self.update();
+        }
+        self.readback.request();
+        self.update();
+        self.readback
+            .read(&self.render_device)
+            .expect("main image was not rendered")
+    }
+}
+
+/// Renders `AnticData` with `memory_mode`, prepared by `setup`, on GPU in a windowless
+/// Bevy app and returns RGBA pixels of its main image.
+pub fn render_offscreen(memory_mode: MemoryMode, setup: impl FnOnce(&mut AnticData)) -> Vec<u8> {
+    let mut renderer = OffscreenRenderer::new(memory_mode);
+    setup(renderer.antic_data_mut());
+    renderer.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_offscreen, OffscreenRenderer};
+    use crate::{
+        software::{self, Frame, FRAME_WIDTH},
+        AnticData, GTIARegs, MemoryMode, ModeLineDescr,
+    };
+
+    fn setup(antic_data: &mut AnticData) {
+        let regs = GTIARegs {
+            col: [0x00, 0x00, 0x00, 0x00, 0x28, 0xca, 0x94, 0x46, 0x00],
+            ..Default::default()
+        };
+        for scan_line in 0..8 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        // characters 1 at $4000, glyph rows %10000000 and %01010101 in charset at $e000
+        antic_data.set_atari_memory(0x4000, &[1; 40]);
+        antic_data.set_atari_memory(0xe008, &[0x80, 0x55, 0x80, 0x55, 0x80, 0x55, 0x80, 0x55]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 2,
+            scan_line: 8,
+            width: 320,
+            height: 8,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            chbase: 0xe0,
+            ..Default::default()
+        });
+        antic_data.config.debug_scan_line = -1;
+    }
+
+    #[test]
+    #[ignore = "requires GPU"]
+    fn test_render_offscreen() {
+        let pixels = render_offscreen(MemoryMode::AddressSpace, setup);
+
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        setup(&mut antic_data);
+        let mut frame = Frame::default();
+        software::render(&antic_data, &mut frame);
+
+        let mode_line = 0..8 * FRAME_WIDTH * 4;
+        assert_ne!(pixels[32 * 4..33 * 4], pixels[33 * 4..34 * 4]);
+        for (gpu, cpu) in pixels[mode_line.clone()]
+            .iter()
+            .zip(&frame.pixels[mode_line])
+        {
+            assert!((*gpu as i32 - *cpu as i32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    #[ignore = "requires GPU"]
+    fn test_collisions_odd_scan_line() {
+        let mut renderer = OffscreenRenderer::with_collisions(MemoryMode::AddressSpace);
+        let antic_data = renderer.antic_data_mut();
+        antic_data.set_atari_memory(0x4000, &[0xff; 40]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 15,
+            scan_line: 8,
+            width: 320,
+            height: 2,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            ..Default::default()
+        });
+        // player 0 only on the second scan line of the hires playfield
+        let mut regs = GTIARegs::default();
+        antic_data.set_gtia_regs(0, &regs);
+        regs.hposp[0] = 0x80;
+        regs.grafp[0] = 0xff;
+        antic_data.set_gtia_regs(1, &regs);
+        let collisions_data = antic_data.collisions_data.clone().unwrap();
+        collisions_data.set_max_latency(Some(0));
+        for _ in 0..4 {
+            renderer.update();
+        }
+
+        assert!(collisions_data.data_frame().is_some());
+        assert!(collisions_data.scan_line(0).is_empty());
+        assert_eq!(collisions_data.scan_line(1).player_playfield(0), 0x4);
+    }
+}
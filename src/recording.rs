@@ -0,0 +1,574 @@
+use std::io::{self, Read, Write};
+
+use bevy::{
+    core::Time,
+    ecs::prelude::*,
+    prelude::{Assets, Handle},
+};
+
+use crate::{snapshot::MAX_SNAPSHOT_SIZE, AnticData, Snapshot, VideoStandard};
+
+pub const RECORDING_MAGIC: &[u8; 8] = b"ANTICREC";
+pub const RECORDING_VERSION: u16 = 1;
+
+/// Default number of frames between keyframes.
+pub const KEYFRAME_INTERVAL: usize = 50;
+
+/// Frame state encoded as XOR delta to the previous frame (or to zeros for keyframes),
+/// compressed with zero run-length encoding.
+#[derive(Clone)]
+struct EncodedFrame {
+    keyframe: bool,
+    /// length of decoded state
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Sequence of frames, every frame stored as `Snapshot`.
+///
+/// Binary format (all numbers little-endian):
+/// * magic `ANTICREC`, version `u16`
+/// * frame rate `f32`, `u32` number of frames
+/// * frames: kind `u8` (0 - keyframe, 1 - delta), `u32` decoded length,
+///   `u32` encoded length followed by encoded data
+///
+/// Encoded data is a sequence of pairs of `varint` lengths: number of zero bytes
+/// and number of literal bytes, followed by literal bytes.
+#[derive(Clone)]
+pub struct Recording {
+    /// frames per second, see `VideoStandard::frame_rate`
+    pub fps: f32,
+    frames: Vec<EncodedFrame>,
+}
+
+impl Recording {
+    pub fn new(fps: f32) -> Self {
+        Self {
+            fps,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Decodes state of frame `index`, starting from the nearest keyframe.
+    pub fn snapshot(&self, index: usize) -> io::Result<Snapshot> {
+        Snapshot::read(self.state(index, None)?.as_slice())
+    }
+
+    /// Serialized snapshot of frame `index`. Decoding starts from `prev` state if it
+    /// precedes `index` and no keyframe is in between.
+    fn state(&self, index: usize, prev: Option<(usize, &[u8])>) -> io::Result<Vec<u8>> {
+        if index >= self.frames.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame {} out of {}", index, self.frames.len()),
+            ));
+        }
+        let keyframe = (0..=index)
+            .rev()
+            .find(|&i| self.frames[i].keyframe)
+            .ok_or_else(|| invalid_data("recording does not start with keyframe"))?;
+        let (start, mut state) = match prev {
+            Some((prev_index, prev)) if prev_index <= index && prev_index >= keyframe => {
+                (prev_index + 1, prev.to_vec())
+            }
+            _ => (keyframe, Vec::new()),
+        };
+        for frame in &self.frames[start..=index] {
+            if frame.keyframe {
+                state.clear();
+            }
+            if state.len() != frame.len {
+                if !frame.keyframe {
+                    return Err(invalid_data("delta frame of different length"));
+                }
+                state.resize(frame.len, 0);
+            }
+            decode_xor(&frame.data, &mut state)?;
+        }
+        Ok(state)
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let w = &mut writer;
+        w.write_all(RECORDING_MAGIC)?;
+        w.write_all(&RECORDING_VERSION.to_le_bytes())?;
+        w.write_all(&self.fps.to_le_bytes())?;
+        w.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        for frame in &self.frames {
+            w.write_all(&[if frame.keyframe { 0 } else { 1 }])?;
+            w.write_all(&(frame.len as u32).to_le_bytes())?;
+            w.write_all(&(frame.data.len() as u32).to_le_bytes())?;
+            w.write_all(&frame.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let r = &mut reader;
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != RECORDING_MAGIC {
+            return Err(invalid_data("not an antic recording"));
+        }
+        let version = u16::from_le_bytes(read_array(r)?);
+        if version != RECORDING_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported recording version {}",
+                version
+            )));
+        }
+        let fps = f32::from_le_bytes(read_array(r)?);
+        if fps.is_nan() || fps <= 0.0 {
+            return Err(invalid_data(format!("invalid frame rate {}", fps)));
+        }
+        let count = u32::from_le_bytes(read_array(r)?) as usize;
+        let mut frames = Vec::with_capacity(count.min(1 << 16));
+        for _ in 0..count {
+            let [kind] = read_array(r)?;
+            let keyframe = match kind {
+                0 => true,
+                1 => false,
+                _ => return Err(invalid_data(format!("invalid frame kind {}", kind))),
+            };
+            let len = u32::from_le_bytes(read_array(r)?) as usize;
+            if len > MAX_SNAPSHOT_SIZE {
+                return Err(invalid_data(format!("invalid frame length {}", len)));
+            }
+            let data_len = u32::from_le_bytes(read_array(r)?) as usize;
+            let mut data = Vec::new();
+            r.take(data_len as u64).read_to_end(&mut data)?;
+            if data.len() != data_len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            frames.push(EncodedFrame {
+                keyframe,
+                len,
+                data,
+            });
+        }
+        if matches!(frames.first(), Some(frame) if !frame.keyframe) {
+            return Err(invalid_data("recording does not start with keyframe"));
+        }
+        Ok(Self { fps, frames })
+    }
+}
+
+/// Records states of `AnticData` frame by frame.
+pub struct Recorder {
+    recording: Recording,
+    prev: Vec<u8>,
+    /// frames recorded since the last keyframe
+    since_keyframe: usize,
+    /// number of frames between keyframes, limits decoding work when seeking
+    pub keyframe_interval: usize,
+}
+
+impl Recorder {
+    pub fn new(fps: f32) -> Self {
+        Self {
+            recording: Recording::new(fps),
+            prev: Vec::new(),
+            since_keyframe: 0,
+            keyframe_interval: KEYFRAME_INTERVAL,
+        }
+    }
+
+    /// Recorder with frame rate of `standard`.
+    pub fn for_standard(standard: VideoStandard) -> Self {
+        Self::new(standard.frame_rate() as f32)
+    }
+
+    /// Appends state of the current frame of `antic_data`.
+    /// Should be called after the frame is complete, before `AnticData::clear`.
+    pub fn push(&mut self, antic_data: &AnticData) {
+        let mut state = Vec::new();
+        Snapshot::capture(antic_data)
+            .write(&mut state)
+            .expect("write to Vec failed");
+        let keyframe = self.recording.is_empty()
+            || self.since_keyframe + 1 >= self.keyframe_interval
+            || state.len() != self.prev.len();
+        self.since_keyframe = if keyframe { 0 } else { self.since_keyframe + 1 };
+        let data = if keyframe {
+            encode_xor(&state, None)
+        } else {
+            encode_xor(&state, Some(&self.prev))
+        };
+        self.recording.frames.push(EncodedFrame {
+            keyframe,
+            len: state.len(),
+            data,
+        });
+        self.prev = state;
+    }
+
+    pub fn len(&self) -> usize {
+        self.recording.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recording.is_empty()
+    }
+
+    pub fn finish(self) -> Recording {
+        self.recording
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid_data("truncated frame data"))?;
+        *data = rest;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("invalid varint"))
+}
+
+/// Encodes `state` XORed with `prev` (zeros if `None`) as runs of zeros and literals.
+pub(crate) fn encode_xor(state: &[u8], prev: Option<&[u8]>) -> Vec<u8> {
+    let delta: Vec<u8> = match prev {
+        Some(prev) => state.iter().zip(prev).map(|(a, b)| a ^ b).collect(),
+        None => state.to_vec(),
+    };
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < delta.len() {
+        let zeros = delta[pos..].iter().take_while(|&&v| v == 0).count();
+        pos += zeros;
+        let literals = delta[pos..].iter().take_while(|&&v| v != 0).count();
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend_from_slice(&delta[pos..pos + literals]);
+        pos += literals;
+    }
+    out
+}
+
+/// XORs `state` with delta encoded by `encode_xor`.
+pub(crate) fn decode_xor(mut data: &[u8], state: &mut [u8]) -> io::Result<()> {
+    let out_of_range = || invalid_data("frame data out of range");
+    let mut pos: usize = 0;
+    while !data.is_empty() {
+        pos = pos
+            .checked_add(read_varint(&mut data)?)
+            .ok_or_else(out_of_range)?;
+        let literals = read_varint(&mut data)?;
+        let end = pos.checked_add(literals).ok_or_else(out_of_range)?;
+        if literals > data.len() || end > state.len() {
+            return Err(out_of_range());
+        }
+        for (dst, v) in state[pos..end].iter_mut().zip(data) {
+            *dst ^= v;
+        }
+        data = &data[literals..];
+        pos = end;
+    }
+    Ok(())
+}
+
+/// Replays `Recording` into `AnticData` of the same entity, at recording frame rate.
+#[derive(Component)]
+pub struct AnticReplay {
+    recording: Recording,
+    position: usize,
+    paused: bool,
+    elapsed: f32,
+    /// last decoded state, speeds up sequential playback
+    state: Option<(usize, Vec<u8>)>,
+    /// frame restored into `AnticData`
+    restored: Option<usize>,
+}
+
+impl AnticReplay {
+    pub fn new(recording: Recording) -> Self {
+        Self {
+            recording,
+            position: 0,
+            paused: false,
+            elapsed: 0.0,
+            state: None,
+            restored: None,
+        }
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    /// Index of the displayed frame.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Playback stops at the last frame, `true` if it was reached.
+    pub fn is_finished(&self) -> bool {
+        self.position + 1 >= self.recording.len()
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.elapsed = 0.0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume()
+        } else {
+            self.pause()
+        }
+    }
+
+    /// Pauses playback and advances it by a single frame.
+    pub fn step(&mut self) {
+        self.pause();
+        if !self.is_finished() {
+            self.position += 1;
+        }
+    }
+
+    /// Moves playback to frame `index`, clamped to the recording length.
+    pub fn seek(&mut self, index: usize) {
+        self.position = index.min(self.recording.len().saturating_sub(1));
+        self.elapsed = 0.0;
+    }
+
+    fn advance(&mut self, delta_seconds: f32) {
+        if self.paused {
+            return;
+        }
+        let frame_time = 1.0 / self.recording.fps;
+        self.elapsed += delta_seconds;
+        while self.elapsed >= frame_time && !self.is_finished() {
+            self.elapsed -= frame_time;
+            self.position += 1;
+        }
+        if self.is_finished() {
+            self.elapsed = 0.0;
+        }
+    }
+
+    fn snapshot(&mut self) -> io::Result<Snapshot> {
+        let prev = self
+            .state
+            .as_ref()
+            .map(|(index, state)| (*index, state.as_slice()));
+        let state = self.recording.state(self.position, prev)?;
+        let snapshot = Snapshot::read(state.as_slice())?;
+        self.state = Some((self.position, state));
+        Ok(snapshot)
+    }
+}
+
+pub fn antic_replay(
+    time: Res<Time>,
+    mut antic_data_assets: ResMut<Assets<AnticData>>,
+    mut query: Query<(&Handle<AnticData>, &mut AnticReplay)>,
+) {
+    for (handle, mut replay) in query.iter_mut() {
+        if replay.recording.is_empty() {
+            continue;
+        }
+        replay.advance(time.delta_seconds());
+        if replay.restored == Some(replay.position) {
+            continue;
+        }
+        let snapshot = match replay.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                bevy::log::error!("invalid frame {} of recording: {}", replay.position, err);
+                replay.pause();
+                continue;
+            }
+        };
+        if let Some(antic_data) = antic_data_assets.get_mut(handle) {
+            snapshot.restore(antic_data);
+            replay.restored = Some(replay.position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_xor, encode_xor, write_varint, AnticReplay, Recorder, Recording};
+    use crate::{AnticData, GTIARegs, MemoryMode, ModeLineDescr, Snapshot};
+
+    fn frame(n: usize) -> AnticData {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        antic_data.set_atari_memory(0x4000 + n, &[n as u8 + 1; 4]);
+        let regs = GTIARegs {
+            hposp: [n as u8, 0, 0, 0],
+            ..Default::default()
+        };
+        antic_data.set_gtia_regs(n % 240, &regs);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 2,
+            scan_line: 8 + n,
+            width: 320,
+            height: 8,
+            video_memory_offset: 0x4000,
+            chbase: 0xe0,
+            ..Default::default()
+        });
+        antic_data
+    }
+
+    fn state(antic_data: &AnticData) -> Vec<u8> {
+        let mut state = Vec::new();
+        Snapshot::capture(antic_data).write(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_xor_rle() {
+        let prev = [0, 1, 2, 3, 4, 5, 6, 7, 0, 0];
+        let state = [0, 1, 9, 3, 4, 0, 0, 7, 1, 0];
+        let data = encode_xor(&state, Some(&prev));
+        let mut decoded = prev;
+        decode_xor(&data, &mut decoded).unwrap();
+        assert_eq!(decoded, state);
+
+        let data = encode_xor(&[0; 1000], None);
+        assert_eq!(data, vec![0xe8, 0x07, 0]);
+
+        // run of zeros overflowing position
+        let mut data = Vec::new();
+        write_varint(&mut data, usize::MAX);
+        write_varint(&mut data, 1);
+        data.push(1);
+        assert!(decode_xor(&data, &mut decoded).is_err());
+    }
+
+    #[test]
+    fn test_record_and_seek() {
+        let mut recorder = Recorder::new(50.0);
+        recorder.keyframe_interval = 4;
+        let frames: Vec<_> = (0..10).map(frame).collect();
+        for antic_data in &frames {
+            recorder.push(antic_data);
+        }
+        let mut data = Vec::new();
+        recorder.finish().write(&mut data).unwrap();
+        // 64 KB of memory per frame, mostly unchanged
+        assert!(data.len() < 10 * 4096);
+
+        let recording = Recording::read(data.as_slice()).unwrap();
+        assert_eq!(recording.len(), 10);
+        assert_eq!(recording.fps, 50.0);
+        for index in [9, 0, 5, 4, 7] {
+            let mut restored = AnticData::headless(MemoryMode::Reserved);
+            recording.snapshot(index).unwrap().restore(&mut restored);
+            assert!(state(&restored) == state(&frames[index]));
+        }
+
+        // sequential decoding from the previous state
+        let mut prev = recording.state(1, None).unwrap();
+        for (index, antic_data) in frames.iter().enumerate().skip(2) {
+            prev = recording.state(index, Some((index - 1, &prev))).unwrap();
+            assert!(prev == state(antic_data));
+        }
+        assert!(recording.snapshot(10).is_err());
+    }
+
+    #[test]
+    fn test_invalid_length() {
+        let mut recorder = Recorder::new(50.0);
+        recorder.push(&frame(0));
+        let mut data = Vec::new();
+        recorder.finish().write(&mut data).unwrap();
+        // decoded length of the first frame
+        data[19..23].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Recording::read(data.as_slice()).is_err());
+    }
+
+    fn recording(fps: f32, len: usize) -> Recording {
+        let mut recorder = Recorder::new(fps);
+        for n in 0..len {
+            recorder.push(&frame(n));
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_replay() {
+        // updates at the recording frame rate show every frame
+        for fps in [50.0, 60.0] {
+            let mut replay = AnticReplay::new(recording(fps, 40));
+            for position in 1..=10 {
+                replay.advance(1.0 / fps);
+                assert_eq!(replay.position(), position);
+            }
+        }
+        // 31 updates at 60 Hz are 25.8 frames at 50 fps
+        let mut replay = AnticReplay::new(recording(50.0, 40));
+        for _ in 0..31 {
+            replay.advance(1.0 / 60.0);
+        }
+        assert_eq!(replay.position(), 25);
+        // 21 updates at 50 Hz are 25.2 frames at 60 fps
+        let mut replay = AnticReplay::new(recording(60.0, 40));
+        for _ in 0..21 {
+            replay.advance(1.0 / 50.0);
+        }
+        assert_eq!(replay.position(), 25);
+
+        // stepping pauses playback
+        replay.step();
+        assert!(replay.is_paused());
+        assert_eq!(replay.position(), 26);
+        replay.advance(1.0);
+        assert_eq!(replay.position(), 26);
+
+        replay.seek(1000);
+        assert_eq!(replay.position(), 39);
+        assert!(replay.is_finished());
+        replay.step();
+        assert_eq!(replay.position(), 39);
+
+        // playback stops at the last frame
+        replay.seek(30);
+        replay.resume();
+        replay.advance(10.0);
+        assert_eq!(replay.position(), 39);
+        assert!(replay.is_finished());
+    }
+}
@@ -21,12 +21,22 @@ let COLPM0: i32 = 0x12;
 let COLPF0: i32 = 0x16;
 let COLBK: i32 = 0x1A;
 
+// phase of color subcarrier at the first sample of the main image, see artifacts.rs
+let SUBCARRIER_PHASE: f32 = 90.0;
+
 struct Palette {
     palette: array<vec4<f32>, 256>;
 };
 
 struct AnticConfig {
     debug_scan_line: i32;
+    cnt: i32;
+    chactl_xor: i32;
+    collisions_region: i32;
+    artifacts: i32;
+    pal_blending: i32;
+    first_visible_line: i32;
+    last_visible_line: i32;
 };
 
 
@@ -39,6 +49,10 @@ var<uniform> palette: Palette;
 [[group(0), binding(2)]]
 var<uniform> antic_config: AnticConfig;
 
+// copy of the main image read by PAL blending pass
+[[group(1), binding(0)]]
+var blending_source: texture_2d<f32>;
+
 fn get_gtia_reg(scan_line: i32, k: i32) -> i32 {
     let offset = scan_line * 32 + k;
     let w = offset & 0xff;
@@ -71,6 +85,73 @@ fn get_memory(offset: i32) -> i32 {
     return i32(v.x & 0xffu);
 }
 
+// byte `n` of screen data, memory scan counter wraps on 4K boundary in 64 KB address space
+fn get_screen(video_memory_offset: i32, n: i32) -> i32 {
+# ifdef ADDRESS_SPACE
+    return get_memory((video_memory_offset & 0xf000) | ((video_memory_offset + n) & 0xfff));
+# else
+    return get_memory(video_memory_offset + n);
+# endif
+}
+
+// byte of player / missile graphics fetched by ANTIC DMA from PMBASE memory
+// area: 0 - missiles, 1-4 - players 0-3
+fn get_pm_dma(pmbase: i32, pm_single_line: bool, area: i32, scan_line: i32) -> u32 {
+    let line = scan_line + 8;
+    var offset = 0;
+    if(pm_single_line) {
+        offset = ((pmbase & 0xf8) << 8u) + 0x300 + area * 0x100 + line;
+    } else {
+        offset = ((pmbase & 0xfc) << 8u) + 0x180 + area * 0x80 + line / 2;
+    };
+    return u32(get_memory(offset));
+}
+
+// player graphics: from PMBASE memory if enabled by DMACTL and GRACTL, GRAFP0-3 otherwise
+fn get_grafp(scan_line: i32, pmbase: i32, dmactl: i32) -> vec4<u32> {
+    let gractl = get_gtia_reg(scan_line, 0x1d);
+    if((dmactl & 0x08) != 0 && (gractl & 2) != 0) {
+        let single_line = (dmactl & 0x10) != 0;
+        return vec4<u32>(
+            get_pm_dma(pmbase, single_line, 1, scan_line),
+            get_pm_dma(pmbase, single_line, 2, scan_line),
+            get_pm_dma(pmbase, single_line, 3, scan_line),
+            get_pm_dma(pmbase, single_line, 4, scan_line),
+        );
+    };
+    return get_gtia_reg4(scan_line, 0x0d);
+}
+
+// missile graphics: from PMBASE memory if enabled by DMACTL and GRACTL, GRAFM otherwise
+fn get_grafm(scan_line: i32, pmbase: i32, dmactl: i32) -> u32 {
+    let gractl = get_gtia_reg(scan_line, 0x1d);
+    if((dmactl & 0x04) != 0 && (gractl & 1) != 0) {
+        return get_pm_dma(pmbase, (dmactl & 0x10) != 0, 0, scan_line);
+    };
+    return u32(get_gtia_reg(scan_line, 0x11));
+}
+
+// glyph row displayed on row `y` of character mode line, -1 for blank rows
+fn glyph_row(mode: i32, y: i32, c: i32, chactl: i32) -> i32 {
+    var row = y;
+    if(mode == 3) {
+        // characters $60-$7F have descenders: top two rows are displayed at the bottom
+        let descender = (c & 0x60) == 0x60;
+        if((descender && y < 2) || (!descender && y >= 8) || y >= 10) {
+            return -1;
+        };
+        row = y & 7;
+    } else if(mode == 5 || mode == 7) {
+        // each glyph row is displayed twice
+        row = y / 2;
+    };
+    if((chactl & 4) != 0) {
+        // CHACTL vertical reflect
+        row = row ^ 7;
+    };
+    return row;
+}
+
 fn cond_i32(pred: bool, a: i32, b: i32) -> i32 {
     if(pred) {
         return a;
@@ -78,6 +159,73 @@ fn cond_i32(pred: bool, a: i32, b: i32) -> i32 {
     return b;
 }
 
+// value of hires pixel (modes 2, 3 and 15 with GTIA mode 0), 1 for COLPF1 luminance
+fn hires_pixel(mode: i32, px_scrolled: f32, y: i32, video_memory_offset: i32, charset_memory_offset: i32, chactl: i32) -> i32 {
+    let w = px_scrolled / 8.0;
+    let n = i32(w);
+    let bit_offs = 7u - u32((w - f32(n)) * 8.0);
+    if(mode == 15) {
+        return (get_screen(video_memory_offset, n) >> bit_offs) & 1;
+    };
+    let c = get_screen(video_memory_offset, n);
+    // CHACTL bit 1: inverse video, bit 0: blank characters with bit 7 set
+    let inv = (c >> 7u) & (chactl >> 1u) & 1;
+    let row = glyph_row(mode, y, c, chactl);
+    var byte = 0;
+    if(row >= 0 && !((c & 0x80) != 0 && (chactl & 1) != 0)) {
+        byte = get_memory(charset_memory_offset + (c & 0x7f) * 8 + row);
+    };
+    return ((byte >> bit_offs) & 1) ^ inv;
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if(value <= 0.0031308) {
+        return value * 12.92;
+    };
+    return 1.055 * pow(value, 1.0 / 2.4) - 0.055;
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if(value <= 0.04045) {
+        return value / 12.92;
+    };
+    return pow((value + 0.055) / 1.055, 2.4);
+}
+
+fn srgb_luma(color: vec4<f32>) -> f32 {
+    return 0.299 * linear_to_srgb(color.r) + 0.587 * linear_to_srgb(color.g) + 0.114 * linear_to_srgb(color.b);
+}
+
+// ArtifactMode::Lookup: color register of pixel `x`, -1 for color clocks without artifacts
+fn artifact_lookup(x: i32, p0: i32, p1: i32, p2: i32, colpf1: i32, colpf2: i32) -> i32 {
+    // color clocks start at even pixels
+    let even = (x & 1) == 0;
+    let left = cond_i32(even, p1, p0);
+    let right = cond_i32(even, p2, p1);
+    if(left == right) {
+        return -1;
+    };
+    let lum = (((colpf1 & 0xf) + (colpf2 & 0xf)) / 2) & 0xe;
+    return cond_i32(right == 1, 0x90, 0x20) | lum;
+}
+
+// ArtifactMode::Signal: color of pixel `x` from colors of pixels `x - 1`, `x`, `x + 1`
+fn artifact_signal(x: i32, c0: vec4<f32>, c1: vec4<f32>, c2: vec4<f32>) -> vec4<f32> {
+    let l0 = srgb_luma(c0);
+    let l1 = srgb_luma(c1);
+    let l2 = srgb_luma(c2);
+    // one subcarrier period (4 samples, 2 per pixel) centered at the pixel
+    let phase = (f32(2 * x - 1) * 90.0 + SUBCARRIER_PHASE) * 0.017453292;
+    let quarter = 1.5707964;
+    let u = 0.5 * (l0 * cos(phase) + l1 * cos(phase + quarter) + l1 * cos(phase + 2.0 * quarter) + l2 * cos(phase + 3.0 * quarter));
+    let v = 0.5 * (l0 * sin(phase) + l1 * sin(phase + quarter) + l1 * sin(phase + 2.0 * quarter) + l2 * sin(phase + 3.0 * quarter));
+    let y = (l0 + 2.0 * l1 + l2) / 4.0 - l1;
+    let r = clamp(linear_to_srgb(c1.r) + y + 1.140 * v, 0.0, 1.0);
+    let g = clamp(linear_to_srgb(c1.g) + y - 0.395 * u - 0.581 * v, 0.0, 1.0);
+    let b = clamp(linear_to_srgb(c1.b) + y + 2.032 * u, 0.0, 1.0);
+    return vec4<f32>(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), 1.0);
+}
+
 [[stage(vertex)]]
 fn vertex(vertex: Vertex) -> VertexOutput {
     let world_position = vec4<f32>(vertex.position, 1.0);
@@ -162,7 +310,7 @@ fn collisions_agg_fragment(
         let t1 = textureLoad(memory, vec2<i32>(px + x, py), 0);
         let a = t1[0] | (t1[1] << 16u);
         let b = t1[2] | (t1[3] << 16u);
-        let t2 = textureLoad(memory, vec2<i32>(px + x + 1, py), 0);
+        let t2 = textureLoad(memory, vec2<i32>(px + x, py + 1), 0);
         let c = t2[0] | (t2[1] << 16u);
         let d = t2[2] | (t2[3] << 16u);
         v = v | vec4<u32>(a, b, c, d);
@@ -178,8 +326,13 @@ fn fragment(
 ) -> FragmentOutput {
     let c0 = u32(custom[0]);
     let c1 = u32(custom[1]);
-    let video_memory_offset = i32(custom[2]);
-    let charset_memory_offset = i32(custom[3]);
+    let c2 = u32(custom[2]);
+    let video_memory_offset = i32(c2 & 0xffffu);
+    let chactl = i32((c2 >> 16u) & 0x7u) ^ antic_config.chactl_xor;
+    let dmactl = i32((c2 >> 19u) & 0x7u) << 2u;  // DMACTL bits 2-4
+    let c3 = u32(custom[3]);
+    let charset_memory_offset = i32(c3 & 0xffffu);
+    let pmbase = i32((c3 >> 16u) & 0xffu);
 
     let mode = i32(c0 & 0xffu);
     let start_scan_line = i32((c0 >> 8u) & 0xffu);
@@ -193,7 +346,7 @@ fn fragment(
     let px = x - 192.0 + line_width / 2.0;
 
     let px_scrolled = px + f32(hscrol);  // pixel x position
-    let cy = i32(uv[1] * f32(line_height) * 0.99);
+    let cy = min(i32(uv[1] * f32(line_height)), line_height - 1);
     let y = cy + line_voffset;
     var hires = false;
 
@@ -224,22 +377,22 @@ fn fragment(
 
     if(mode == 0x0 || px < 0.0 || px >= line_width) {
 
-    } else if(mode == 2) {
-        let w = px_scrolled / 8.0;
-        let n = i32(w);
-        let frac = w - f32(n);
-
-        let c = get_memory(video_memory_offset + n);
-        let inv = c >> 7u;
-        let offs = (c & 0x7f) * 8 + y;
-        var byte = get_memory(charset_memory_offset + offs);
-
+    } else if(mode == 2 || mode == 3) {
         if(gtia_mode == 0) {
-            let bit_offs = 7u - u32(frac * 8.0);
-            let pixel_val = (((byte >> bit_offs) & 1) ^ inv);
+            let pixel_val = hires_pixel(mode, px_scrolled, y, video_memory_offset, charset_memory_offset, chactl);
             color_reg_index = 3 - pixel_val;  // pf2 pf1
             hires = true;
         } else {
+            let w = px_scrolled / 8.0;
+            let n = i32(w);
+            let frac = w - f32(n);
+
+            let c = get_screen(video_memory_offset, n);
+            let row = glyph_row(mode, y, c, chactl);
+            var byte = 0;
+            if(row >= 0 && !((c & 0x80) != 0 && (chactl & 1) != 0)) {
+                byte = get_memory(charset_memory_offset + (c & 0x7f) * 8 + row);
+            };
             let bit_offs = 4u - u32(frac * 2.0) * 4u; // nibble offset
             let value = (byte >> bit_offs) & 0xf;
             if(gtia_mode == 1) {
@@ -266,9 +419,9 @@ fn fragment(
         let frac = w - f32(n);
         let bit_offs = 6u - u32(frac * 4.0) * 2u;
 
-        let c = get_memory(video_memory_offset + n);
+        let c = get_screen(video_memory_offset, n);
         let inv = c >> 7u;
-        let offs = (c & 0x7f) * 8 + y;
+        let offs = (c & 0x7f) * 8 + glyph_row(mode, y, c, chactl);
         let byte = get_memory(charset_memory_offset + offs);
         color_reg_index = (byte >> bit_offs) & 3;
         if(inv != 0 && color_reg_index == 3) {
@@ -279,12 +432,9 @@ fn fragment(
         let n = i32(w);
         let frac = w - f32(n);
         let bit_offs = 7u - u32(frac * 8.0);
-        var yy = y;
-        if(mode == 7) {yy = yy / 2;};
-
-        let c = get_memory(video_memory_offset + n);
+        let c = get_screen(video_memory_offset, n);
         let cc = c >> 6u;
-        let offs = (c & 0x3f) * 8 + yy;
+        let offs = (c & 0x3f) * 8 + glyph_row(mode, y, c, chactl);
         let byte = get_memory(charset_memory_offset + offs);
 
         if(((byte >> bit_offs) & 1) > 0) {
@@ -297,14 +447,14 @@ fn fragment(
         let n = i32(w); // byte offset
         let frac = w - f32(n);
         let bit_offs = 6u - u32(frac * 4.0) * 2u; // bit offset in byte
-        let byte = get_memory(video_memory_offset + n);
+        let byte = get_screen(video_memory_offset, n);
         color_reg_index = (byte >> bit_offs) & 3;
     } else if(mode == 9) {
         let w = px_scrolled / 32.0;;
         let n = i32(w); // byte offset
         let frac = w - f32(n);
         let bit_offs = 7u - u32(frac * 8.0);
-        let byte = get_memory(video_memory_offset + n);
+        let byte = get_screen(video_memory_offset, n);
         color_reg_index = (byte >> bit_offs) & 1;
     } else if(mode == 10) {
         let w = px_scrolled / 16.0;
@@ -312,7 +462,7 @@ fn fragment(
         let frac = w - f32(n);
         let bit_offs = 6u - u32(frac * 4.0) * 2u; // bit offset in byte
 
-        let byte = get_memory(video_memory_offset + n);
+        let byte = get_screen(video_memory_offset, n);
         color_reg_index = (byte >> bit_offs) & 3;
     } else if(mode == 11 || mode == 12) {
         let w = px_scrolled / 16.0;
@@ -320,7 +470,7 @@ fn fragment(
         let frac = w - f32(n);
         let bit_offs = 7u - u32(frac * 8.0);
 
-        let byte = get_memory(video_memory_offset + n);
+        let byte = get_screen(video_memory_offset, n);
         color_reg_index = (byte >> bit_offs) & 1;
     } else if(mode == 13 || mode == 14) {
         let w = px_scrolled / 8.0;
@@ -328,21 +478,19 @@ fn fragment(
         let frac = w - f32(n);
         let bit_offs = 6u - u32(frac * 4.0) * 2u; // bit offset in byte
 
-        let byte = get_memory(video_memory_offset + n);
+        let byte = get_screen(video_memory_offset, n);
         color_reg_index = (byte >> bit_offs) & 3;
 
     } else if(mode == 15) {
-        let w = px_scrolled / 8.0;
-        let n = i32(w); // byte offset
-        let frac = w - f32(n);
-        let byte = get_memory(video_memory_offset + n);
-
         if(gtia_mode == 0) {
-            let bit_offs = 7u - u32(frac * 8.0);
-            let pixel_val = (byte >> bit_offs) & 1;
+            let pixel_val = hires_pixel(mode, px_scrolled, y, video_memory_offset, charset_memory_offset, chactl);
             color_reg_index = 3 - pixel_val;
             hires = true;
         } else {
+            let w = px_scrolled / 8.0;
+            let n = i32(w); // byte offset
+            let frac = w - f32(n);
+            let byte = get_screen(video_memory_offset, n);
             let bit_offs = 4u - u32(frac * 2.0) * 4u; // nibble offset
             let value = (byte >> bit_offs) & 0xf;
             if(gtia_mode == 1) {
@@ -377,8 +525,19 @@ fn fragment(
 
     let vpx = vec4<f32>(px);
 
+    // VDELAY: in two-line resolution delayed objects are updated on odd scan lines only,
+    // so on even scan lines graphics of the previous line are displayed
+    let pm_single_line = (dmactl & 0x10) != 0;
+    let delayed = !pm_single_line && (scan_line & 1) == 0 && scan_line > 0;
+    let vdelay = u32(get_gtia_reg(scan_line, 0x1c)) * u32(delayed);
+    let prev_scan_line = max(scan_line - 1, 0);
+
+    let mdelay = vdelay & 0xfu;
+    let mdelay_mask = ((mdelay & 1u) * 0x03u) | ((mdelay & 2u) * 0x06u) | ((mdelay & 4u) * 0x0cu) | ((mdelay & 8u) * 0x18u);
+    let grafm = (get_grafm(scan_line, pmbase, dmactl) & ~mdelay_mask) | (get_grafm(prev_scan_line, pmbase, dmactl) & mdelay_mask);
+
     let missile_shift = vec4<u32>(0u, 2u, 4u, 6u);
-    let mdata = vec4<u32>(u32(get_gtia_reg(scan_line, 0x11))) >> missile_shift;
+    let mdata = vec4<u32>(grafm) >> missile_shift;
 
     let msize_ = (vec4<u32>(u32(get_gtia_reg(scan_line, 0x0c))) >> missile_shift) & vec4<u32>(0x3u);
     let msize = vec4<f32>(vec4<i32>(4) << msize_);
@@ -394,7 +553,8 @@ fn fragment(
 
     let psize_ = get_gtia_reg4(scan_line, 0x08) & vec4<u32>(0x3u);
     let psize = vec4<f32>(vec4<i32>(16) << psize_);
-    let data = get_gtia_reg4(scan_line, 0x0d);
+    let pdelay_mask = ((vec4<u32>(vdelay) >> vec4<u32>(4u, 5u, 6u, 7u)) & vec4<u32>(1u)) * vec4<u32>(0xffu);
+    let data = (get_grafp(scan_line, pmbase, dmactl) & ~pdelay_mask) | (get_grafp(prev_scan_line, pmbase, dmactl) & pdelay_mask);
 
     let p = get_pm_pixels(vpx, 8.0, scan_line, psize, hposp, data);
 
@@ -440,8 +600,6 @@ fn fragment(
         color_reg = (color_reg & 0xf0) | (get_gtia_reg(scan_line, COLPF0 + 1) & 0xf);
     }
 
-    // TODO - do not check collisions on HBLANK
-
     let p0_ = bool(p[0]);
     let p1_ = bool(p[1]);
     let p2_ = bool(p[2]);
@@ -471,13 +629,54 @@ fn fragment(
     let p2pl = cond_i32(p2_, (player_bits & ~4) << 8u, 0);
     let p3pl = cond_i32(p3_, (player_bits & ~8) << 12u, 0);
 
-    let o_CollisionsTarget = vec4<u32>(
+    var o_CollisionsTarget = vec4<u32>(
         u32(m0pf | m1pf | m2pf | m3pf),
         u32(p0pf | p1pf | p2pf | p3pf),
         u32(m0pl | m1pl | m2pl | m3pl),
         u32(p0pl | p1pl | p2pl | p3pl),
     );
+
+    // collisions region: 0 - playfield, 1 - visible line (no HBLANK), 2 - full line
+    let collisions_region = antic_config.collisions_region;
+    let in_playfield = px >= 0.0 && px < line_width;
+    let in_visible = x >= 4.0 && x < 380.0;
+    if(!(collisions_region == 2 || (collisions_region == 1 && in_visible) || (collisions_region == 0 && in_playfield))) {
+        o_CollisionsTarget = vec4<u32>(0u, 0u, 0u, 0u);
+    }
     var out_color = palette.palette[color_reg];
+    // NTSC artifacts of hires playfield not covered by players, see artifacts.rs
+    if(hires && sf2 && antic_config.artifacts != 0) {
+        let pixel_val = 3 - color_reg_index;
+        // pixels outside of playfield continue the pattern
+        var left = pixel_val;
+        if(px - 1.0 >= 0.0) {
+            left = hires_pixel(mode, px_scrolled - 1.0, y, video_memory_offset, charset_memory_offset, chactl);
+        };
+        var right = pixel_val;
+        if(px + 1.0 < line_width) {
+            right = hires_pixel(mode, px_scrolled + 1.0, y, video_memory_offset, charset_memory_offset, chactl);
+        };
+        let colpf1 = get_gtia_reg(scan_line, COLPF0 + 1);
+        let colpf2 = get_gtia_reg(scan_line, COLPF0 + 2);
+        if(antic_config.artifacts == 1) {
+            let reg = artifact_lookup(i32(x), left, pixel_val, right, colpf1, colpf2);
+            if(reg >= 0) {
+                out_color = palette.palette[reg];
+            };
+        } else {
+            let lum_color = (colpf2 & 0xf0) | (colpf1 & 0xf);
+            out_color = artifact_signal(
+                i32(x),
+                palette.palette[cond_i32(left == 1, lum_color, colpf2)],
+                palette.palette[cond_i32(pixel_val == 1, lum_color, colpf2)],
+                palette.palette[cond_i32(right == 1, lum_color, colpf2)],
+            );
+        };
+    };
+    // scan lines outside of visible area of the video standard
+    if(scan_line < antic_config.first_visible_line || scan_line >= antic_config.last_visible_line) {
+        out_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    };
     if(scan_line == antic_config.debug_scan_line) {
         let alpha = 0.5;
         out_color = vec4<f32>(alpha * vec3<f32>(1.0, 0.0, 0.0) + (1.0 - alpha) * out_color.rgb, 1.0);
@@ -485,3 +684,24 @@ fn fragment(
 
     return FragmentOutput(out_color, o_CollisionsTarget);
 }
+
+// U and V components of linear `color`, computed on sRGB values
+fn srgb_chroma(color: vec4<f32>) -> vec2<f32> {
+    let r = linear_to_srgb(color.r);
+    let b = linear_to_srgb(color.b);
+    let y = srgb_luma(color);
+    return vec2<f32>(0.492 * (b - y), 0.877 * (r - y));
+}
+
+// PAL delay line: chroma of scan line averaged with the previous one, see pal_blending.rs
+[[stage(fragment)]]
+fn pal_blending_fragment([[builtin(position)]] position: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    let xy = vec2<i32>(position.xy);
+    let current = textureLoad(blending_source, xy, 0);
+    let previous = textureLoad(blending_source, vec2<i32>(xy.x, max(xy.y - 1, 0)), 0);
+    let d = (srgb_chroma(previous) - srgb_chroma(current)) / 2.0;
+    let r = clamp(linear_to_srgb(current.r) + 1.140 * d.y, 0.0, 1.0);
+    let g = clamp(linear_to_srgb(current.g) - 0.395 * d.x - 0.581 * d.y, 0.0, 1.0);
+    let b = clamp(linear_to_srgb(current.b) + 2.032 * d.x, 0.0, 1.0);
+    return vec4<f32>(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), current.a);
+}
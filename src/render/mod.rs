@@ -19,12 +19,17 @@ use bevy::{
 use crevice::std140::{AsStd140, Std140};
 pub mod pass;
 use crate::resources::{AtariPalette, AnticConfig};
-use pass::{AnticPhase, CollisionsAggPhase};
+use pass::{AnticPhase, CollisionsAggPhase, PalBlendingPhase};
 use std::sync::Arc;
 use wgpu::BufferDescriptor;
 
-pub use crate::antic_data::{AnticData, AnticDataInner, CollisionsData};
-use crate::ANTIC_SHADER_HANDLE;
+pub use crate::antic_data::{
+    AnticData, AnticDataInner, CollisionsData, MemoryMode, GTIA_REGS_MEMORY,
+};
+use crate::{
+    software::{FRAME_HEIGHT, FRAME_WIDTH},
+    ImageReadback, ANTIC_SHADER_HANDLE, SCAN_LINES,
+};
 
 #[derive(Clone)]
 pub struct GpuAnticCollisionsData {
@@ -45,10 +50,15 @@ pub struct GpuAnticDataInner {
     index_buffer: Buffer,
     vertex_buffer: Buffer,
     data_texture: Texture,
+    data_texture_size: Extent3d,
     main_image_handle: Handle<Image>,
     main_bind_group: BindGroup,
     _data_texture_view: TextureView,
     collisions: Option<GpuAnticCollisionsData>,
+    /// copy of the main image, source of PAL blending pass
+    pal_blending_texture: Texture,
+    _pal_blending_texture_view: TextureView,
+    pal_blending_bind_group: BindGroup,
 }
 
 #[derive(Clone)]
@@ -56,17 +66,28 @@ pub struct GpuAnticData {
     inner: Arc<GpuAnticDataInner>,
     index_count: u32,
     config: AnticConfig,
+    palette: AtariPalette,
+    memory_mode: MemoryMode,
+    image_readback: Option<ImageReadback>,
+}
+
+pub fn data_texture_size(memory_mode: MemoryMode) -> Extent3d {
+    Extent3d {
+        width: 256,
+        height: ((GTIA_REGS_MEMORY + memory_mode.memory_size()) / 256) as u32,
+        depth_or_array_layers: 1,
+    }
 }
 
-pub const DATA_TEXTURE_SIZE: Extent3d = Extent3d {
-    width: 256,
-    height: 11 * 4 * 4 + (240 * 32 / 256),
+pub const MAIN_IMAGE_SIZE: Extent3d = Extent3d {
+    width: FRAME_WIDTH as u32,
+    height: FRAME_HEIGHT as u32,
     depth_or_array_layers: 1,
 };
 
 pub const COLLISIONS_TEXTURE_SIZE: Extent3d = Extent3d {
     width: 384,
-    height: 240,
+    height: SCAN_LINES as u32,
     depth_or_array_layers: 1,
 };
 
@@ -78,6 +99,7 @@ impl RenderAsset for AnticData {
         SRes<RenderQueue>,
         SRes<AnticPipeline>,
         SRes<CollisionsAggPipeline>,
+        SRes<PalBlendingPipeline>,
         SResMut<HashMap<Handle<Image>, GpuAnticData>>,
     );
     fn extract_asset(&self) -> Self::ExtractedAsset {
@@ -86,10 +108,18 @@ impl RenderAsset for AnticData {
 
     fn prepare_asset(
         extracted_asset: Self::ExtractedAsset,
-        (render_device, render_queue, pipeline, collisions_agg_pipeline, cache): &mut SystemParamItem<Self::Param>,
+        (render_device, render_queue, pipeline, collisions_agg_pipeline, pal_blending_pipeline, cache): &mut SystemParamItem<Self::Param>,
     ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
 
         let inner = extracted_asset.inner.read();
+        // data texture is sized for memory mode, which can be changed by `Snapshot::restore`
+        let mode_changed = matches!(
+            cache.get(&extracted_asset.main_image_handle),
+            Some(gpu_data) if gpu_data.memory_mode != inner.memory_mode
+        );
+        if mode_changed {
+            cache.remove(&extracted_asset.main_image_handle);
+        }
         let entry = cache.entry(extracted_asset.main_image_handle.clone());
         let main_image_handle = extracted_asset.main_image_handle.clone();
         let collisions_data = extracted_asset
@@ -101,11 +131,16 @@ impl RenderAsset for AnticData {
                 inner: Self::create_gpu_data(
                     render_device,
                     pipeline,
+                    pal_blending_pipeline,
                     main_image_handle,
                     collisions_data,
+                    inner.memory_mode,
                 ),
                 index_count: 0,
                 config: extracted_asset.config,
+                palette: inner.palette,
+                memory_mode: inner.memory_mode,
+                image_readback: None,
             };
             render_queue.write_buffer(
                 &gpu_data.inner.palette_buffer,
@@ -139,6 +174,12 @@ impl RenderAsset for AnticData {
             gpu_data
         });
 
+        gpu_data.image_readback = extracted_asset.image_readback.clone();
+
+        if let Some(collisions_data) = &extracted_asset.collisions_data {
+            collisions_data.set_hitclr(&inner.memory[..GTIA_REGS_MEMORY]);
+        }
+
         // TODO - mesh change detection
         let mesh = extracted_asset.create_mesh();
         let vertex_data = mesh.get_vertex_buffer_data();
@@ -158,7 +199,7 @@ impl RenderAsset for AnticData {
                 bytes_per_row: Some(std::num::NonZeroU32::new(256).unwrap()),
                 rows_per_image: None,
             },
-            DATA_TEXTURE_SIZE,
+            gpu_data.inner.data_texture_size,
         );
         if extracted_asset.config != gpu_data.config {
             gpu_data.config = extracted_asset.config;
@@ -168,6 +209,14 @@ impl RenderAsset for AnticData {
                 extracted_asset.config.as_std140().as_bytes(),
             );
         }
+        if inner.palette != gpu_data.palette {
+            gpu_data.palette = inner.palette;
+            render_queue.write_buffer(
+                &gpu_data.inner.palette_buffer,
+                0,
+                inner.palette.as_std140().as_bytes(),
+            );
+        }
         Ok(gpu_data.clone())
     }
 }
@@ -176,11 +225,14 @@ impl AnticData {
     fn create_gpu_data(
         render_device: &RenderDevice,
         pipeline: &AnticPipeline,
+        pal_blending_pipeline: &PalBlendingPipeline,
         main_image_handle: Handle<Image>,
         collisions_data: Option<(&CollisionsAggPipeline, CollisionsData)>,
+        memory_mode: MemoryMode,
     ) -> Arc<GpuAnticDataInner> {
+        let data_texture_size = data_texture_size(memory_mode);
         let texture_descriptor = wgpu::TextureDescriptor {
-            size: DATA_TEXTURE_SIZE,
+            size: data_texture_size,
             dimension: TextureDimension::D2,
             format: wgpu::TextureFormat::R8Uint,
             label: Some("data_texture"),
@@ -240,6 +292,26 @@ impl AnticData {
             layout: &pipeline.data_layout,
         });
 
+        let pal_blending_texture = render_device.create_texture(&wgpu::TextureDescriptor {
+            size: MAIN_IMAGE_SIZE,
+            dimension: TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            label: Some("pal_blending_texture"),
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let pal_blending_texture_view =
+            pal_blending_texture.create_view(&TextureViewDescriptor::default());
+        let pal_blending_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&pal_blending_texture_view),
+            }],
+            label: Some("pal_blending_bind_group"),
+            layout: &pal_blending_pipeline.image_layout,
+        });
+
         let collisions = if let Some((collisions_agg_pipeline, data)) = collisions_data {
             let collisions_agg_texture_descriptor = wgpu::TextureDescriptor {
                 size: crate::COLLISIONS_AGG_TEXTURE_SIZE,
@@ -317,9 +389,13 @@ impl AnticData {
             index_buffer,
             vertex_buffer,
             data_texture,
+            data_texture_size,
             _data_texture_view: data_texture_view,
             main_bind_group,
             collisions,
+            pal_blending_texture,
+            _pal_blending_texture_view: pal_blending_texture_view,
+            pal_blending_bind_group,
         })
     }
 }
@@ -381,6 +457,40 @@ impl FromWorld for AnticPipeline {
         AnticPipeline { data_layout }
     }
 }
+
+/// Pipeline of PAL blending pass: draws mode lines of `AnticData` again,
+/// blending them from a copy of the main image.
+pub struct PalBlendingPipeline {
+    data_layout: BindGroupLayout,
+    image_layout: BindGroupLayout,
+}
+
+impl FromWorld for PalBlendingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let data_layout = world.get_resource::<AnticPipeline>().unwrap().data_layout.clone();
+        let render_device = world.get_resource::<RenderDevice>().unwrap();
+
+        let image_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    view_dimension: TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    multisampled: false,
+                },
+                count: None,
+            }],
+            label: Some("pal_blending_image_layout"),
+        });
+
+        PalBlendingPipeline {
+            data_layout,
+            image_layout,
+        }
+    }
+}
+
 impl FromWorld for CollisionsAggPipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.get_resource::<RenderDevice>().unwrap();
@@ -406,12 +516,18 @@ impl FromWorld for CollisionsAggPipeline {
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct AnticPipelineKey {
     collisions: bool,
+    /// memory mode is `MemoryMode::AddressSpace`
+    address_space: bool,
 }
 
 impl SpecializedPipeline for AnticPipeline {
     type Key = AnticPipelineKey;
 
     fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = vec![];
+        if key.address_space {
+            shader_defs.push("ADDRESS_SPACE".to_string());
+        }
         let targets = if key.collisions {
             vec![
                 ColorTargetState {
@@ -466,7 +582,7 @@ impl SpecializedPipeline for AnticPipeline {
             },
             fragment: Some(FragmentState {
                 shader: ANTIC_SHADER_HANDLE.typed::<Shader>(),
-                shader_defs: vec![],
+                shader_defs,
                 entry_point: "fragment".into(),
                 targets,
             }),
@@ -486,6 +602,68 @@ impl SpecializedPipeline for AnticPipeline {
     }
 }
 
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct PalBlendingPipelineKey;
+
+impl SpecializedPipeline for PalBlendingPipeline {
+    type Key = PalBlendingPipelineKey;
+
+    fn specialize(&self, _key: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: None,
+            vertex: VertexState {
+                shader_defs: vec![],
+                shader: ANTIC_SHADER_HANDLE.typed::<Shader>(),
+                buffers: vec![VertexBufferLayout {
+                    array_stride: 36,
+                    step_mode: VertexStepMode::Vertex,
+                    attributes: vec![
+                        // same mesh as in `AnticPipeline`
+                        VertexAttribute {
+                            format: VertexFormat::Float32x3,
+                            offset: 0,
+                            shader_location: 0,
+                        },
+                        VertexAttribute {
+                            format: VertexFormat::Float32x2,
+                            offset: 12,
+                            shader_location: 1,
+                        },
+                        VertexAttribute {
+                            format: VertexFormat::Float32x4,
+                            offset: 20,
+                            shader_location: 2,
+                        },
+                    ],
+                }],
+                entry_point: "vertex".into(),
+            },
+            fragment: Some(FragmentState {
+                shader: ANTIC_SHADER_HANDLE.typed::<Shader>(),
+                shader_defs: vec![],
+                entry_point: "pal_blending_fragment".into(),
+                targets: vec![ColorTargetState {
+                    format: TextureFormat::Rgba8UnormSrgb,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                }],
+            }),
+            layout: Some(vec![self.data_layout.clone(), self.image_layout.clone()]),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                unclipped_depth: false,
+            },
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct CollisionsAggPipelineKey(u32);
 
@@ -554,12 +732,16 @@ impl SpecializedPipeline for CollisionsAggPipeline {
 pub fn queue_meshes(
     draw_functions: Res<DrawFunctions<AnticPhase>>,
     collisions_agg_draw_functions: Res<DrawFunctions<CollisionsAggPhase>>,
+    pal_blending_draw_functions: Res<DrawFunctions<PalBlendingPhase>>,
     antic_pipeline: Res<AnticPipeline>,
     collisions_agg_pipeline: Res<CollisionsAggPipeline>,
+    pal_blending_pipeline: Res<PalBlendingPipeline>,
     mut render_phase: ResMut<RenderPhase<AnticPhase>>,
     mut collisions_agg_render_phase: ResMut<RenderPhase<CollisionsAggPhase>>,
+    mut pal_blending_render_phase: ResMut<RenderPhase<PalBlendingPhase>>,
     mut pipelines: ResMut<SpecializedPipelines<AnticPipeline>>,
     mut collision_agg_pipelines: ResMut<SpecializedPipelines<CollisionsAggPipeline>>,
+    mut pal_blending_pipelines: ResMut<SpecializedPipelines<PalBlendingPipeline>>,
     mut pipeline_cache: ResMut<RenderPipelineCache>,
     atari_datas: Res<RenderAssets<AnticData>>,
     antic_data_query: Query<(Entity, &Handle<AnticData>)>,
@@ -569,8 +751,13 @@ pub fn queue_meshes(
         .read()
         .get_id::<SetCollisionsAggPipeline>()
         .unwrap();
+    let pal_blending_draw_function = pal_blending_draw_functions
+        .read()
+        .get_id::<SetPalBlendingPipeline>()
+        .unwrap();
     render_phase.items.clear();
     collisions_agg_render_phase.items.clear();
+    pal_blending_render_phase.items.clear();
 
     for (entity, antic_data_handle) in antic_data_query.iter() {
         let atari_data = atari_datas.get(antic_data_handle).unwrap();
@@ -578,7 +765,10 @@ pub fn queue_meshes(
         let pipeline = pipelines.specialize(
             &mut pipeline_cache,
             &antic_pipeline,
-            AnticPipelineKey { collisions },
+            AnticPipelineKey {
+                collisions,
+                address_space: atari_data.memory_mode == MemoryMode::AddressSpace,
+            },
         );
         render_phase.add(AnticPhase {
             main_image_handle: atari_data.inner.main_image_handle.clone(),
@@ -601,6 +791,20 @@ pub fn queue_meshes(
                 antic_data_handle: antic_data_handle.clone(),
             });
         }
+        if atari_data.config.pal_blending != 0 {
+            let pal_blending_pipeline = pal_blending_pipelines.specialize(
+                &mut pipeline_cache,
+                &pal_blending_pipeline,
+                PalBlendingPipelineKey,
+            );
+            pal_blending_render_phase.add(PalBlendingPhase {
+                pipeline: pal_blending_pipeline,
+                entity,
+                draw_function: pal_blending_draw_function,
+                antic_data_handle: antic_data_handle.clone(),
+                main_image_handle: atari_data.inner.main_image_handle.clone(),
+            });
+        }
     }
 }
 
@@ -684,3 +888,45 @@ impl RenderCommand<CollisionsAggPhase> for SetCollisionsAggPipeline {
         RenderCommandResult::Success
     }
 }
+
+pub struct SetPalBlendingPipeline;
+impl RenderCommand<PalBlendingPhase> for SetPalBlendingPipeline {
+    type Param = (
+        SRes<RenderPipelineCache>,
+        SRes<RenderAssets<AnticData>>,
+        SQuery<Read<Handle<AnticData>>>,
+    );
+    fn render<'w>(
+        _view: Entity,
+        item: &PalBlendingPhase,
+        (pipeline_cache, atari_data_assets, query): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let antic_data_handle = query.get(item.entity).unwrap();
+        let gpu_atari_data = atari_data_assets
+            .into_inner()
+            .get(antic_data_handle)
+            .unwrap();
+
+        let index_count = gpu_atari_data.index_count;
+        if index_count == 0 {
+            return RenderCommandResult::Failure;
+        }
+        if let Some(pipeline) = pipeline_cache.into_inner().get(item.pipeline) {
+            pass.set_render_pipeline(pipeline);
+            pass.set_bind_group(0, &gpu_atari_data.inner.main_bind_group, &[]);
+            pass.set_bind_group(1, &gpu_atari_data.inner.pal_blending_bind_group, &[]);
+            pass.set_vertex_buffer(0, gpu_atari_data.inner.vertex_buffer.slice(..));
+            pass.set_index_buffer(
+                gpu_atari_data
+                    .inner
+                    .index_buffer
+                    .slice(0..(index_count * 2) as u64),
+                0,
+                wgpu::IndexFormat::Uint16,
+            );
+            pass.draw_indexed(0..index_count, 0, 0..1);
+        }
+        RenderCommandResult::Success
+    }
+}
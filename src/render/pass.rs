@@ -13,7 +13,11 @@ use bevy::{
 };
 use wgpu::{LoadOp, Operations, RenderPassColorAttachment, RenderPassDescriptor};
 
-use crate::AnticData;
+use crate::{
+    readback::MAIN_IMAGE_BYTES_PER_ROW,
+    software::{FRAME_HEIGHT, FRAME_WIDTH},
+    AnticData, CollisionsData,
+};
 pub struct AnticPhase {
     pub pipeline: CachedPipelineId,
     pub entity: Entity,
@@ -28,6 +32,13 @@ pub struct CollisionsAggPhase {
     pub draw_function: DrawFunctionId,
     pub antic_data_handle: Handle<AnticData>,
 }
+pub struct PalBlendingPhase {
+    pub pipeline: CachedPipelineId,
+    pub entity: Entity,
+    pub draw_function: DrawFunctionId,
+    pub antic_data_handle: Handle<AnticData>,
+    pub main_image_handle: Handle<Image>,
+}
 
 impl PhaseItem for AnticPhase {
     type SortKey = Entity;
@@ -57,6 +68,20 @@ impl PhaseItem for CollisionsAggPhase {
     }
 }
 
+impl PhaseItem for PalBlendingPhase {
+    type SortKey = Entity;
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        self.entity
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
 #[derive(Default)]
 pub struct AnticPassNode;
 
@@ -143,6 +168,75 @@ impl Node for AnticPassNode {
     }
 }
 
+/// Blends chroma of scan lines drawn in this frame with previous scan lines.
+/// Main image is copied first, so the shader reads unblended previous lines.
+#[derive(Default)]
+pub struct PalBlendingNode;
+
+impl Node for PalBlendingNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let antic_data_assets = world.get_resource::<RenderAssets<AnticData>>().unwrap();
+        let image_assets = world.get_resource::<RenderAssets<Image>>().unwrap();
+
+        let render_phase = world.get_resource::<RenderPhase<PalBlendingPhase>>().unwrap();
+        for item in render_phase.items.iter() {
+            let main_image = if let Some(image) = image_assets.get(&item.main_image_handle) {
+                image
+            } else {
+                continue;
+            };
+            let gpu_antic_data = match antic_data_assets.get(&item.antic_data_handle) {
+                Some(gpu_antic_data) => gpu_antic_data,
+                None => continue,
+            };
+            render_context.command_encoder.copy_texture_to_texture(
+                main_image.texture.as_image_copy(),
+                gpu_antic_data.inner.pal_blending_texture.as_image_copy(),
+                super::MAIN_IMAGE_SIZE,
+            );
+
+            let pass_descriptor = RenderPassDescriptor {
+                label: Some("pal_blending_pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: &main_image.texture_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            };
+
+            {
+                let draw_functions = world
+                    .get_resource::<DrawFunctions<PalBlendingPhase>>()
+                    .unwrap();
+
+                let render_pass = render_context
+                    .command_encoder
+                    .begin_render_pass(&pass_descriptor);
+                let mut draw_functions = draw_functions.write();
+
+                let mut tracked_pass = TrackedRenderPass::new(render_pass);
+                let draw_function = draw_functions.get_mut(item.draw_function).unwrap();
+                draw_function.draw(
+                    world,
+                    &mut tracked_pass,
+                    bevy::ecs::entity::Entity::from_raw(0),
+                    item,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct CollisionsAggNode;
 
@@ -227,8 +321,11 @@ impl Node for CollisionsAggReadNode {
             let collisions = gpu_antic_data.inner.collisions.as_ref().unwrap();
             let copy_size = crate::COLLISIONS_AGG_TEXTURE_SIZE;
 
-            let inner = collisions.data.inner.write();
-            let index = inner.buffer_index;
+            let mut inner = collisions.data.inner.write();
+            let index = match inner.buffer_index {
+                Some(index) => index,
+                None => continue,
+            };
             let buffer = &inner.buffers[index];
             // bevy::log::info!("copy texture to buffer {}", index);
             render_context.command_encoder.copy_texture_to_buffer(
@@ -248,6 +345,63 @@ impl Node for CollisionsAggReadNode {
                 },
                 copy_size,
             );
+            CollisionsData::copied(&mut inner, index);
+        }
+        Ok(())
+    }
+}
+
+/// Copies main image to `ImageReadback` buffer when requested.
+#[derive(Default)]
+pub struct MainImageReadNode;
+
+impl Node for MainImageReadNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let antic_data_assets = world.get_resource::<RenderAssets<AnticData>>().unwrap();
+        let image_assets = world.get_resource::<RenderAssets<Image>>().unwrap();
+        let render_phase = world.get_resource::<RenderPhase<AnticPhase>>().unwrap();
+        for item in render_phase.items.iter() {
+            let image_readback = match antic_data_assets
+                .get(&item.antic_data_handle)
+                .and_then(|data| data.image_readback.as_ref())
+            {
+                Some(image_readback) => image_readback,
+                None => continue,
+            };
+            let main_image = if let Some(image) = image_assets.get(&item.main_image_handle) {
+                image
+            } else {
+                continue;
+            };
+            let mut inner = image_readback.inner.write();
+            if !inner.requested {
+                continue;
+            }
+            render_context.command_encoder.copy_texture_to_buffer(
+                main_image.texture.as_image_copy(),
+                wgpu::ImageCopyBuffer {
+                    buffer: &inner.buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(
+                            std::num::NonZeroU32::new(MAIN_IMAGE_BYTES_PER_ROW as u32).unwrap(),
+                        ),
+                        rows_per_image: None,
+                    },
+                },
+                wgpu::Extent3d {
+                    width: FRAME_WIDTH as u32,
+                    height: FRAME_HEIGHT as u32,
+                    depth_or_array_layers: 1,
+                },
+            );
+            inner.requested = false;
+            inner.copied = true;
         }
         Ok(())
     }
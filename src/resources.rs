@@ -1,22 +1,74 @@
-use bevy::render::color::Color;
+use std::io;
+
+use bevy::{reflect::TypeUuid, render::color::Color};
 use bytemuck::{Pod, Zeroable};
 use crevice::std140::{Std140, AsStd140};
 
+use crate::{software::linear_to_srgb, ArtifactMode, CollisionsRegion, VideoStandard, SCAN_LINES};
+
 #[repr(C)]
-#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, TypeUuid)]
+#[uuid = "3b3a5d1e-7f0c-4c8e-9a47-2d6f1b8e5c90"]
 pub struct AtariPalette(pub [[f32; 4]; 256]);
 
 impl Default for AtariPalette {
     fn default() -> Self {
-        let data = include_bytes!("altirra.pal");
-        let colors = data[..]
+        Self::for_standard(VideoStandard::Pal)
+    }
+}
+
+impl AtariPalette {
+    /// Default palette of `standard`: Altirra PAL palette, NTSC one is generated
+    /// by `PaletteGenerator::for_standard(VideoStandard::Ntsc)`.
+    pub fn for_standard(standard: VideoStandard) -> Self {
+        let data: &[u8] = match standard {
+            VideoStandard::Pal => include_bytes!("altirra.pal"),
+            VideoStandard::Ntsc => include_bytes!("ntsc.pal"),
+        };
+        Self::from_rgb(data).unwrap()
+    }
+
+    /// Palette from 256 sRGB triplets, as stored in `.pal` files. Returns `None` if `data`
+    /// is shorter than 768 bytes.
+    pub fn from_rgb(data: &[u8]) -> Option<Self> {
+        if data.len() < 256 * 3 {
+            return None;
+        }
+        let colors = data[..256 * 3]
             .chunks(3)
             .map(|c| Color::rgba_u8(c[0], c[1], c[2], 255));
         let colors = colors.map(|c| c.as_linear_rgba_f32()).collect::<Vec<_>>();
 
         let mut arr = [[0f32; 4]; 256];
         arr.clone_from_slice(&colors[..256]);
-        Self(arr)
+        Some(Self(arr))
+    }
+
+    /// Palette from contents of a palette file:
+    /// * 768 bytes - `.pal` file with 256 RGB triplets (Altirra, Atari800),
+    /// * 772 bytes - Adobe `.act` file, RGB triplets followed by color count and transparent index,
+    /// * 1024 bytes - 256 4-byte entries, the 4th byte is ignored.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let rgb = match data.len() {
+            768 | 772 => data[..768].to_vec(),
+            1024 => data.chunks(4).flat_map(|c| c[..3].to_vec()).collect(),
+            len => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid palette size: {} bytes, expected 768, 772 or 1024",
+                        len
+                    ),
+                ))
+            }
+        };
+        Ok(Self::from_rgb(&rgb).unwrap())
+    }
+
+    /// Colors as stored in the main image (sRGB).
+    pub fn to_srgb(&self) -> [[u8; 3]; 256] {
+        self.0
+            .map(|c| [linear_to_srgb(c[0]), linear_to_srgb(c[1]), linear_to_srgb(c[2])])
     }
 }
 
@@ -30,10 +82,54 @@ unsafe impl Std140 for AtariPalette {
 pub struct AnticConfig {
     pub debug_scan_line: i32,
     pub cnt: i32,
+    /// XORed with CHACTL of every mode line, toggle bit 1 to blink inverse video cursor.
+    pub chactl_xor: i32,
+    /// `CollisionsRegion` in which collisions are detected.
+    pub collisions_region: i32,
+    /// `ArtifactMode` of hires playfield.
+    pub artifacts: i32,
+    /// Non-zero enables PAL delay line emulation: chroma of every rendered scan line
+    /// is averaged with the previous one.
+    pub pal_blending: i32,
+    /// First row of the main image shown, set from `VideoStandard::visible_scan_lines`.
+    pub first_visible_line: i32,
+    /// Row of the main image following the last one shown.
+    pub last_visible_line: i32,
 }
 
 impl Default for AnticConfig {
     fn default() -> Self {
-        Self { debug_scan_line: 8, cnt: 0,}
+        Self {
+            debug_scan_line: 8,
+            cnt: 0,
+            chactl_xor: 0,
+            collisions_region: CollisionsRegion::default() as i32,
+            artifacts: ArtifactMode::default() as i32,
+            pal_blending: 0,
+            first_visible_line: 0,
+            last_visible_line: SCAN_LINES as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AtariPalette;
+
+    #[test]
+    fn test_from_bytes() {
+        let rgb: Vec<u8> = (0..768).map(|i| (i % 251) as u8).collect();
+        let palette = AtariPalette::from_rgb(&rgb).unwrap();
+        assert_eq!(AtariPalette::from_bytes(&rgb).unwrap(), palette);
+
+        // ACT with color count and transparent index
+        let mut act = rgb.clone();
+        act.extend([1, 0, 0xff, 0xff]);
+        assert_eq!(AtariPalette::from_bytes(&act).unwrap(), palette);
+
+        let rgbx: Vec<u8> = rgb.chunks(3).flat_map(|c| [c[0], c[1], c[2], 0]).collect();
+        assert_eq!(AtariPalette::from_bytes(&rgbx).unwrap(), palette);
+
+        assert!(AtariPalette::from_bytes(&rgb[..767]).is_err());
     }
 }
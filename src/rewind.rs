@@ -0,0 +1,337 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use bevy::{
+    ecs::prelude::*,
+    prelude::{Assets, Handle},
+};
+
+use crate::{
+    recording::{decode_xor, encode_xor},
+    AnticData, Snapshot,
+};
+
+/// Number of frames kept in `RewindBuffer`, set from `AtariAnticPlugin::rewind_frames`.
+pub(crate) struct RewindFrames(pub usize);
+
+/// Older frame stored as XOR delta to the following one.
+struct Delta {
+    /// length of decoded state
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Ring buffer of recent frame states of `AnticData` attached to the same entity.
+///
+/// Inserted by the plugin when `AtariAnticPlugin::rewind_frames` is not zero. Frames are
+/// recorded while the buffer is live; after `step_back` or `seek` the selected frame is
+/// displayed instead of the current one until `resume` is called.
+#[derive(Component)]
+pub struct RewindBuffer {
+    capacity: usize,
+    /// serialized snapshot of the newest frame
+    latest: Vec<u8>,
+    /// older frames, the oldest first
+    deltas: VecDeque<Delta>,
+    /// number of frames back from the newest one
+    position: usize,
+    rewinding: bool,
+    /// decoded snapshot of `position` frame
+    decoded: Option<(usize, Snapshot)>,
+    /// frame restored into `AnticData`
+    restored: Option<usize>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            position: 0,
+            rewinding: false,
+            decoded: None,
+            restored: None,
+        }
+    }
+
+    /// Number of stored frames.
+    pub fn len(&self) -> usize {
+        if self.latest.is_empty() {
+            0
+        } else {
+            self.deltas.len() + 1
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_empty()
+    }
+
+    /// Appends state of the current frame of `antic_data`, dropping the oldest one if full.
+    pub fn push(&mut self, antic_data: &AnticData) {
+        let mut state = Vec::new();
+        Snapshot::capture(antic_data)
+            .write(&mut state)
+            .expect("write to Vec failed");
+        if !self.latest.is_empty() {
+            // states may differ in length, delta is encoded over the longer one
+            let mut prev = std::mem::take(&mut self.latest);
+            let mut next = state.clone();
+            let len = prev.len();
+            let padded_len = len.max(next.len());
+            prev.resize(padded_len, 0);
+            next.resize(padded_len, 0);
+            self.deltas.push_back(Delta {
+                len,
+                data: encode_xor(&prev, Some(&next)),
+            });
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = state;
+        self.decoded = None;
+    }
+
+    /// Serialized snapshot of the frame `back` frames before the newest one.
+    fn state(&self, back: usize) -> io::Result<Vec<u8>> {
+        let mut state = self.latest.clone();
+        for delta in self.deltas.iter().rev().take(back) {
+            state.resize(state.len().max(delta.len), 0);
+            decode_xor(&delta.data, &mut state)?;
+            state.truncate(delta.len);
+        }
+        Ok(state)
+    }
+
+    /// Snapshot of the frame `back` frames before the newest one (clamped to the oldest).
+    pub fn snapshot(&self, back: usize) -> io::Result<Snapshot> {
+        let back = back.min(self.len().saturating_sub(1));
+        Snapshot::read(self.state(back)?.as_slice())
+    }
+
+    /// Writes the frame `back` frames before the newest one in `Snapshot` format.
+    pub fn export(&self, back: usize, writer: impl Write) -> io::Result<()> {
+        self.snapshot(back)?.write(writer)
+    }
+
+    /// Number of frames back from the newest one of the displayed frame.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// `true` if a stored frame is displayed instead of the current one.
+    pub fn is_rewinding(&self) -> bool {
+        self.rewinding
+    }
+
+    /// Stops recording and displays frame `back` frames before the newest one.
+    pub fn seek(&mut self, back: usize) {
+        self.rewinding = true;
+        self.position = back.min(self.len().saturating_sub(1));
+    }
+
+    /// Displays the previous stored frame.
+    pub fn step_back(&mut self) {
+        self.seek(self.position + 1);
+    }
+
+    /// Displays the next stored frame.
+    pub fn step_forward(&mut self) {
+        self.seek(self.position.saturating_sub(1));
+    }
+
+    /// Returns to the current frame and resumes recording.
+    pub fn resume(&mut self) {
+        self.rewinding = false;
+        self.position = 0;
+        self.decoded = None;
+        self.restored = None;
+    }
+
+    fn displayed(&mut self) -> io::Result<&Snapshot> {
+        if !matches!(self.decoded, Some((position, _)) if position == self.position) {
+            self.decoded = Some((self.position, self.snapshot(self.position)?));
+        }
+        Ok(&self.decoded.as_ref().unwrap().1)
+    }
+}
+
+/// Records frames of every `AnticData` entity, or displays the selected frame while rewinding.
+pub(crate) fn antic_rewind(
+    mut commands: Commands,
+    rewind_frames: Res<RewindFrames>,
+    mut antic_data_assets: ResMut<Assets<AnticData>>,
+    mut query: Query<(Entity, &Handle<AnticData>, Option<&mut RewindBuffer>)>,
+) {
+    for (entity, handle, buffer) in query.iter_mut() {
+        match buffer {
+            Some(mut buffer) if buffer.is_rewinding() => {
+                if buffer.restored == Some(buffer.position) {
+                    continue;
+                }
+                let snapshot = match buffer.displayed() {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        bevy::log::error!("invalid rewind frame: {}", err);
+                        buffer.resume();
+                        continue;
+                    }
+                };
+                if let Some(antic_data) = antic_data_assets.get_mut(handle) {
+                    snapshot.restore(antic_data);
+                    buffer.restored = Some(buffer.position);
+                }
+            }
+            Some(mut buffer) => {
+                if let Some(antic_data) = antic_data_assets.get(handle) {
+                    buffer.push(antic_data);
+                }
+            }
+            None => {
+                let mut buffer = RewindBuffer::new(rewind_frames.0);
+                if let Some(antic_data) = antic_data_assets.get(handle) {
+                    buffer.push(antic_data);
+                }
+                commands.entity(entity).insert(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::{AddAsset, AssetPlugin},
+        core::CorePlugin,
+        prelude::{App, Assets, CoreStage},
+    };
+
+    use super::{antic_rewind, RewindBuffer, RewindFrames};
+    use crate::{AnticData, GTIARegs, MemoryMode, ModeLineDescr, Snapshot};
+
+    fn state(antic_data: &AnticData) -> Vec<u8> {
+        let mut state = Vec::new();
+        Snapshot::capture(antic_data).write(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut buffer = RewindBuffer::new(3);
+        let mut antic_data = AnticData::headless(MemoryMode::Reserved);
+        let mut states = vec![];
+        for n in 0..5 {
+            antic_data.clear();
+            // reserved memory and mode lines of different length in every frame
+            let offset = antic_data.reserve_antic_memory(40 * (n + 1), &mut |data| {
+                data.fill(n as u8 + 1)
+            });
+            for row in 0..=n {
+                antic_data.insert_mode_line(&ModeLineDescr {
+                    mode: 2,
+                    scan_line: 8 + row * 8,
+                    width: 320,
+                    height: 8,
+                    video_memory_offset: offset + row * 40,
+                    ..Default::default()
+                });
+            }
+            buffer.push(&antic_data);
+            states.push(state(&antic_data));
+        }
+        assert_eq!(buffer.len(), 3);
+        for back in 0..3 {
+            let mut restored = AnticData::headless(MemoryMode::Reserved);
+            buffer.snapshot(back).unwrap().restore(&mut restored);
+            assert!(state(&restored) == states[4 - back]);
+        }
+
+        buffer.step_back();
+        buffer.step_back();
+        buffer.step_back();
+        assert!(buffer.is_rewinding());
+        assert_eq!(buffer.position(), 2);
+        buffer.step_forward();
+        assert_eq!(buffer.position(), 1);
+        let mut exported = Vec::new();
+        buffer.export(buffer.position(), &mut exported).unwrap();
+        assert!(exported == states[3]);
+        buffer.resume();
+        assert!(!buffer.is_rewinding());
+        assert_eq!(buffer.position(), 0);
+    }
+
+    #[test]
+    fn test_antic_rewind() {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .add_plugin(AssetPlugin)
+            .add_asset::<AnticData>()
+            .insert_resource(RewindFrames(3))
+            .add_system_to_stage(CoreStage::PostUpdate, antic_rewind);
+        let handle = app
+            .world
+            .get_resource_mut::<Assets<AnticData>>()
+            .unwrap()
+            .add(AnticData::headless(MemoryMode::Reserved));
+        let entity = app.world.spawn().insert(handle.clone()).id();
+        let set_frame = |app: &mut App, n: u8| {
+            let mut assets = app.world.get_resource_mut::<Assets<AnticData>>().unwrap();
+            let regs = GTIARegs {
+                hposp: [n, 0, 0, 0],
+                ..Default::default()
+            };
+            assets.get_mut(&handle).unwrap().set_gtia_regs(0, &regs);
+        };
+        let hposp0 = |app: &App| {
+            let assets = app.world.get_resource::<Assets<AnticData>>().unwrap();
+            let antic_data = assets.get(&handle).unwrap();
+            let inner = antic_data.inner.read();
+            inner.memory[0]
+        };
+        let buffer_len = |app: &App| app.world.get::<RewindBuffer>(entity).unwrap().len();
+
+        // buffer is inserted with the first frame
+        app.update();
+        assert_eq!(buffer_len(&app), 1);
+        for n in 1..5 {
+            set_frame(&mut app, n);
+            app.update();
+        }
+        assert_eq!(buffer_len(&app), 3);
+
+        // frames are restored and not recorded while rewinding
+        app.world
+            .get_mut::<RewindBuffer>(entity)
+            .unwrap()
+            .step_back();
+        app.update();
+        assert_eq!(hposp0(&app), 3);
+        // unchanged position is not restored again
+        set_frame(&mut app, 10);
+        app.update();
+        assert_eq!(hposp0(&app), 10);
+        app.world
+            .get_mut::<RewindBuffer>(entity)
+            .unwrap()
+            .step_back();
+        app.update();
+        assert_eq!(hposp0(&app), 2);
+        assert_eq!(buffer_len(&app), 3);
+
+        let mut buffer = app.world.get_mut::<RewindBuffer>(entity).unwrap();
+        buffer.resume();
+        assert_eq!(buffer.position(), 0);
+        set_frame(&mut app, 11);
+        app.update();
+        assert!(!app.world.get::<RewindBuffer>(entity).unwrap().is_rewinding());
+        let mut restored = AnticData::headless(MemoryMode::Reserved);
+        let buffer = app.world.get::<RewindBuffer>(entity).unwrap();
+        buffer.snapshot(0).unwrap().restore(&mut restored);
+        assert_eq!(restored.inner.read().memory[0], 11);
+    }
+}
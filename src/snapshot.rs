@@ -0,0 +1,436 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    antic_data::GTIA_REGS_MEMORY,
+    display_list::{FIRST_SCAN_LINE, LAST_SCAN_LINE, SCAN_LINES},
+    resources::{AnticConfig, AtariPalette},
+    AnticData, GTIARegs, MemoryMode, ModeLineDescr, VideoStandard,
+};
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"ANTICSNP";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Size of magic, version, memory mode, video standard, config and palette.
+const HEADER_SIZE: usize = 8 + 2 + 2 + 8 * 4 + 256 * 4 * 4;
+/// Size of a mode line record.
+const MODE_LINE_SIZE: usize = 1 + 8 * 4 + 6;
+/// Upper bound of the size of a serialized snapshot: 64 KB of memory
+/// and a mode line for every scan line.
+pub const MAX_SNAPSHOT_SIZE: usize =
+    HEADER_SIZE + 4 + GTIA_REGS_MEMORY + 4 + 0x10000 + 4 + SCAN_LINES * MODE_LINE_SIZE;
+
+/// Logical state of a frame: everything needed to render identical picture.
+///
+/// Binary format (all numbers little-endian):
+/// * magic `ANTICSNP`, version `u16`
+/// * memory mode `u8` (0 - reserved, 1 - address space)
+/// * video standard `u8` (0 - PAL, 1 - NTSC)
+/// * `AnticConfig` as eight `i32`
+/// * palette as 256 x 4 `f32` (linear RGBA)
+/// * GTIA registers, `u32` number of scan lines followed by 32 bytes for every scan line
+/// * antic memory, `u32` length followed by data
+/// * mode lines, `u32` count followed by records of `ModeLineDescr` fields
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub memory_mode: MemoryMode,
+    pub video_standard: VideoStandard,
+    pub config: AnticConfig,
+    pub palette: AtariPalette,
+    /// `GTIARegs` of every visible scan line
+    pub gtia_regs: Vec<u8>,
+    /// used part of antic memory: reserved memory in `MemoryMode::Reserved`, 64 KB otherwise
+    pub memory: Vec<u8>,
+    pub mode_lines: Vec<ModeLineDescr>,
+}
+
+impl Snapshot {
+    pub fn capture(antic_data: &AnticData) -> Self {
+        let inner = antic_data.inner.read();
+        let memory_end = GTIA_REGS_MEMORY
+            + match inner.memory_mode {
+                MemoryMode::Reserved => inner.memory_used,
+                MemoryMode::AddressSpace => inner.memory_mode.memory_size(),
+            };
+        Self {
+            memory_mode: inner.memory_mode,
+            video_standard: inner.video_standard,
+            config: antic_data.config,
+            palette: inner.palette,
+            gtia_regs: inner.memory[..GTIA_REGS_MEMORY].to_vec(),
+            memory: inner.memory[GTIA_REGS_MEMORY..memory_end].to_vec(),
+            mode_lines: inner.mode_lines.clone(),
+        }
+    }
+
+    /// Replaces state of `antic_data` with the snapshot, setting GTIA registers and mode lines
+    /// with `set_gtia_regs` and `insert_mode_line`, as a program driving `AnticData` would.
+    pub fn restore(&self, antic_data: &mut AnticData) {
+        antic_data.clear();
+        antic_data.config = self.config;
+        {
+            let mut inner = antic_data.inner.write();
+            inner.memory_mode = self.memory_mode;
+            inner.video_standard = self.video_standard;
+            inner.palette = self.palette;
+            inner.memory.clear();
+            inner
+                .memory
+                .resize(GTIA_REGS_MEMORY + self.memory_mode.memory_size(), 0);
+            let memory_end = GTIA_REGS_MEMORY + self.memory.len();
+            inner.memory[GTIA_REGS_MEMORY..memory_end].copy_from_slice(&self.memory);
+            if self.memory_mode == MemoryMode::Reserved {
+                inner.memory_used = self.memory.len();
+            }
+        }
+        for (scan_line, regs) in self.gtia_regs.chunks_exact(32).enumerate() {
+            let regs = unsafe { std::ptr::read_unaligned(regs.as_ptr() as *const GTIARegs) };
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        for mode_line in &self.mode_lines {
+            antic_data.insert_mode_line(mode_line);
+        }
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let w = &mut writer;
+        w.write_all(SNAPSHOT_MAGIC)?;
+        w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        let memory_mode: u8 = match self.memory_mode {
+            MemoryMode::Reserved => 0,
+            MemoryMode::AddressSpace => 1,
+        };
+        let video_standard: u8 = match self.video_standard {
+            VideoStandard::Pal => 0,
+            VideoStandard::Ntsc => 1,
+        };
+        w.write_all(&[memory_mode, video_standard])?;
+
+        let config = &self.config;
+        for value in [
+            config.debug_scan_line,
+            config.cnt,
+            config.chactl_xor,
+            config.collisions_region,
+            config.artifacts,
+            config.pal_blending,
+            config.first_visible_line,
+            config.last_visible_line,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        for value in self.palette.0.iter().flatten() {
+            w.write_all(&value.to_le_bytes())?;
+        }
+
+        write_u32(w, (self.gtia_regs.len() / 32) as u32)?;
+        w.write_all(&self.gtia_regs)?;
+        write_u32(w, self.memory.len() as u32)?;
+        w.write_all(&self.memory)?;
+
+        write_u32(w, self.mode_lines.len() as u32)?;
+        for mode_line in &self.mode_lines {
+            w.write_all(&[mode_line.mode])?;
+            for value in [
+                mode_line.scan_line,
+                mode_line.width,
+                mode_line.height,
+                mode_line.n_bytes,
+                mode_line.line_voffset,
+                mode_line.data_offset,
+                mode_line.video_memory_offset,
+                mode_line.charset_memory_offset,
+            ] {
+                write_u32(w, value as u32)?;
+            }
+            w.write_all(&[
+                mode_line.chbase,
+                mode_line.pmbase,
+                mode_line.hscrol,
+                mode_line.dli as u8,
+                mode_line.chactl,
+                mode_line.dmactl,
+            ])?;
+        }
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let r = &mut reader;
+        let magic: [u8; 8] = read_array(r)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid_data("not an antic snapshot"));
+        }
+        let version = u16::from_le_bytes(read_array(r)?);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {}",
+                version
+            )));
+        }
+        let memory_mode = match read_u8(r)? {
+            0 => MemoryMode::Reserved,
+            1 => MemoryMode::AddressSpace,
+            mode => return Err(invalid_data(format!("invalid memory mode {}", mode))),
+        };
+        let video_standard = match read_u8(r)? {
+            0 => VideoStandard::Pal,
+            1 => VideoStandard::Ntsc,
+            standard => return Err(invalid_data(format!("invalid video standard {}", standard))),
+        };
+
+        let config = AnticConfig {
+            debug_scan_line: read_i32(r)?,
+            cnt: read_i32(r)?,
+            chactl_xor: read_i32(r)?,
+            collisions_region: read_i32(r)?,
+            artifacts: read_i32(r)?,
+            pal_blending: read_i32(r)?,
+            first_visible_line: read_i32(r)?,
+            last_visible_line: read_i32(r)?,
+        };
+        let mut palette = AtariPalette([[0.0; 4]; 256]);
+        for value in palette.0.iter_mut().flatten() {
+            *value = f32::from_le_bytes(read_array(r)?);
+        }
+
+        let scan_lines = read_u32(r)? as usize;
+        if scan_lines * 32 != GTIA_REGS_MEMORY {
+            return Err(invalid_data(format!(
+                "invalid number of scan lines {}",
+                scan_lines
+            )));
+        }
+        let gtia_regs = read_vec(r, GTIA_REGS_MEMORY)?;
+        let memory_len = read_u32(r)? as usize;
+        if memory_len > memory_mode.memory_size() {
+            return Err(invalid_data(format!(
+                "memory size {} exceeds {}",
+                memory_len,
+                memory_mode.memory_size()
+            )));
+        }
+        let memory = read_vec(r, memory_len)?;
+
+        let count = read_u32(r)? as usize;
+        if count > SCAN_LINES {
+            return Err(invalid_data(format!(
+                "invalid number of mode lines {}",
+                count
+            )));
+        }
+        let mut mode_lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mode = read_u8(r)?;
+            let mut values = [0; 8];
+            for value in values.iter_mut() {
+                *value = read_u32(r)? as usize;
+            }
+            let [chbase, pmbase, hscrol, dli, chactl, dmactl] = read_array(r)?;
+            let [
+                scan_line,
+                width,
+                height,
+                n_bytes,
+                line_voffset,
+                data_offset,
+                video_memory_offset,
+                charset_memory_offset,
+            ] = values;
+            if scan_line < FIRST_SCAN_LINE || scan_line + height > LAST_SCAN_LINE {
+                return Err(invalid_data(format!(
+                    "mode line outside of visible area: scan line {}, height {}",
+                    scan_line, height
+                )));
+            }
+            if video_memory_offset >= 0x10000 || charset_memory_offset >= 0x10000 {
+                return Err(invalid_data("mode line memory offset out of range"));
+            }
+            mode_lines.push(ModeLineDescr {
+                mode,
+                scan_line,
+                width,
+                height,
+                n_bytes,
+                line_voffset,
+                data_offset,
+                chbase,
+                pmbase,
+                hscrol,
+                video_memory_offset,
+                charset_memory_offset,
+                dli: dli > 0,
+                chactl,
+                dmactl,
+            });
+        }
+
+        Ok(Self {
+            memory_mode,
+            video_standard,
+            config,
+            palette,
+            gtia_regs,
+            memory,
+            mode_lines,
+        })
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    Ok(i32::from_le_bytes(read_array(r)?))
+}
+
+fn read_vec(r: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+    use crate::{
+        software::{self, Frame},
+        AnticData, GTIARegs, MemoryMode, ModeLineDescr, OffscreenRenderer, VideoStandard,
+    };
+
+    fn antic_data() -> AnticData {
+        let mut antic_data = AnticData::headless(MemoryMode::Reserved);
+        antic_data.config.chactl_xor = 2;
+        let regs = GTIARegs {
+            col: [0x38, 0x88, 0xc8, 0x1a, 0x26, 0x0e, 0x94, 0x56, 0x02],
+            ..Default::default()
+        };
+        for scan_line in 0..240 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        let charset_memory_offset = antic_data.reserve_antic_memory(1024, &mut |data| {
+            for (i, v) in data.iter_mut().enumerate() {
+                *v = (i * 0x35) as u8;
+            }
+        });
+        let video_memory_offset = antic_data.reserve_antic_memory(40, &mut |data| {
+            for (i, v) in data.iter_mut().enumerate() {
+                *v = i as u8 * 3;
+            }
+        });
+        for row in 0..4 {
+            antic_data.insert_mode_line(&ModeLineDescr {
+                mode: 2,
+                scan_line: 24 + row * 8,
+                width: 320,
+                height: 8,
+                video_memory_offset,
+                charset_memory_offset,
+                ..Default::default()
+            });
+        }
+        antic_data
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let antic_data = antic_data();
+        let mut data = Vec::new();
+        Snapshot::capture(&antic_data).write(&mut data).unwrap();
+        assert_eq!(&data[..8], b"ANTICSNP");
+
+        let mut restored = AnticData::headless(MemoryMode::AddressSpace);
+        Snapshot::read(data.as_slice())
+            .unwrap()
+            .restore(&mut restored);
+        assert_eq!(restored.config, antic_data.config);
+
+        let (mut expected, mut actual) = (Frame::default(), Frame::default());
+        software::render(&antic_data, &mut expected);
+        software::render(&restored, &mut actual);
+        assert!(expected.pixels == actual.pixels);
+
+        let mut data2 = Vec::new();
+        Snapshot::capture(&restored).write(&mut data2).unwrap();
+        assert_eq!(data, data2);
+    }
+
+    #[test]
+    #[ignore = "requires GPU"]
+    fn test_restore_memory_mode() {
+        // charset above reserved memory size
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        let regs = GTIARegs {
+            col: [0x00, 0x00, 0x00, 0x00, 0x28, 0xca, 0x94, 0x46, 0x00],
+            ..Default::default()
+        };
+        for scan_line in 0..8 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        antic_data.set_atari_memory(0x4000, &[1; 40]);
+        antic_data.set_atari_memory(0xe008, &[0x80, 0x55, 0x80, 0x55, 0x80, 0x55, 0x80, 0x55]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 2,
+            scan_line: 8,
+            width: 320,
+            height: 8,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            chbase: 0xe0,
+            ..Default::default()
+        });
+        let mut expected = Frame::default();
+        software::render(&antic_data, &mut expected);
+
+        // GPU data of reserved memory is already created when the snapshot is restored
+        let mut renderer = OffscreenRenderer::new(MemoryMode::Reserved);
+        renderer.render();
+        Snapshot::capture(&antic_data).restore(renderer.antic_data_mut());
+        let pixels = renderer.render();
+        for (gpu, cpu) in pixels.iter().zip(&expected.pixels) {
+            assert!((*gpu as i32 - *cpu as i32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_video_standard() {
+        let mut antic_data = antic_data();
+        antic_data.set_video_standard(VideoStandard::Ntsc);
+        antic_data.config.artifacts = 1;
+        antic_data.config.pal_blending = 1;
+        let mut data = Vec::new();
+        Snapshot::capture(&antic_data).write(&mut data).unwrap();
+        let snapshot = Snapshot::read(data.as_slice()).unwrap();
+        assert_eq!(snapshot.video_standard, VideoStandard::Ntsc);
+        assert_eq!(snapshot.config, antic_data.config);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut data = Vec::new();
+        Snapshot::capture(&antic_data()).write(&mut data).unwrap();
+        assert!(Snapshot::read(&data[..data.len() - 1]).is_err());
+        data[8] = 5;
+        assert!(Snapshot::read(data.as_slice()).is_err());
+        data[0] = b'X';
+        assert!(Snapshot::read(data.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,856 @@
+use crate::{
+    antic_data::{mode_line_custom, paused_mode_line, GTIA_REGS_MEMORY},
+    artifacts::{self, ArtifactMode},
+    display_list::SCAN_LINES,
+    pal_blending,
+    resources::{AnticConfig, AtariPalette},
+    AnticData, MemoryMode,
+};
+
+/// Width of rendered frame in pixels.
+pub const FRAME_WIDTH: usize = 384;
+/// Height of rendered frame in pixels.
+pub const FRAME_HEIGHT: usize = SCAN_LINES;
+
+const COLPM0: i32 = 0x12;
+const COLPF0: i32 = 0x16;
+const COLBK: i32 = 0x1a;
+
+/// Frame rendered by `render`: RGBA pixels (sRGB, as in the main image) and collisions
+/// of every scan line, packed the same way as `CollisionsData::data`.
+#[derive(Clone)]
+pub struct Frame {
+    pub pixels: Vec<u8>,
+    pub collisions: [u64; FRAME_HEIGHT],
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        // same initial content as image created by `create_main_image`
+        Self {
+            pixels: vec![128; FRAME_WIDTH * FRAME_HEIGHT * 4],
+            collisions: [0; FRAME_HEIGHT],
+        }
+    }
+}
+
+impl Frame {
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let offset = (y * FRAME_WIDTH + x) * 4;
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(&self.pixels[offset..offset + 4]);
+        pixel
+    }
+}
+
+/// Renders mode lines of `antic_data` to `frame` on CPU, producing the same output as `antic.wgsl`.
+///
+/// Like the GPU pass, it draws only scan lines covered by mode lines, leaving others untouched.
+/// With `AnticConfig::pal_blending` drawn scan lines are blended afterwards, as in the PAL
+/// blending pass.
+pub fn render(antic_data: &AnticData, frame: &mut Frame) {
+    let inner = antic_data.inner.read();
+    let memory = Memory {
+        data: &inner.memory,
+        address_space: inner.memory_mode == MemoryMode::AddressSpace,
+    };
+    let config = &antic_data.config;
+    let paused_line =
+        paused_mode_line(inner.scanlines).map(|mode_line| mode_line_custom(&mode_line, 0));
+    let lines = inner
+        .custom
+        .chunks(4)
+        .map(|custom| custom[0])
+        .chain(paused_line);
+
+    let mut drawn = [false; FRAME_HEIGHT];
+    for custom in lines {
+        let line = LineParams::decode(custom, config.chactl_xor);
+        for cy in 0..line.line_height {
+            let y = (line.start_scan_line + cy) as usize;
+            if y >= FRAME_HEIGHT {
+                break;
+            }
+            let mut collisions = 0;
+            for x in 0..FRAME_WIDTH {
+                let (color, pixel_collisions) =
+                    shade(&memory, config, &inner.palette, &line, x as f32 + 0.5, cy);
+                let offset = (y * FRAME_WIDTH + x) * 4;
+                frame.pixels[offset..offset + 4].copy_from_slice(&color);
+                collisions |= pixel_collisions;
+            }
+            frame.collisions[y] = collisions;
+            drawn[y] = true;
+        }
+    }
+    if config.pal_blending != 0 {
+        blend_scan_lines(frame, &drawn);
+    }
+}
+
+/// Port of PAL blending pass: blends every `drawn` scan line with the previous one,
+/// reading scan lines as they were before blending.
+fn blend_scan_lines(frame: &mut Frame, drawn: &[bool; FRAME_HEIGHT]) {
+    let source = frame.pixels.clone();
+    let srgb = |y: usize, x: usize| {
+        let offset = (y * FRAME_WIDTH + x) * 4;
+        [0, 1, 2].map(|i| source[offset + i] as f32 / 255.0)
+    };
+    for y in (0..FRAME_HEIGHT).filter(|&y| drawn[y]) {
+        for x in 0..FRAME_WIDTH {
+            let color = pal_blending::blend(srgb(y, x), srgb(y.saturating_sub(1), x));
+            let offset = (y * FRAME_WIDTH + x) * 4;
+            for (i, c) in color.iter().enumerate() {
+                frame.pixels[offset + i] = (c * 255.0).round() as u8;
+            }
+        }
+    }
+}
+
+/// Mode line parameters decoded from "Vertex_ZCustom" attribute.
+struct LineParams {
+    mode: i32,
+    start_scan_line: i32,
+    line_height: i32,
+    hscrol: i32,
+    line_voffset: i32,
+    line_width: f32,
+    video_memory_offset: i32,
+    chactl: i32,
+    dmactl: i32,
+    charset_memory_offset: i32,
+    pmbase: i32,
+}
+
+impl LineParams {
+    fn decode(custom: [f32; 4], chactl_xor: i32) -> Self {
+        let c0 = custom[0] as u32;
+        let c1 = custom[1] as u32;
+        let c2 = custom[2] as u32;
+        let c3 = custom[3] as u32;
+        Self {
+            mode: (c0 & 0xff) as i32,
+            start_scan_line: ((c0 >> 8) & 0xff) as i32,
+            line_height: ((c0 >> 16) & 0xff) as i32,
+            hscrol: (c1 & 0xff) as i32,
+            line_voffset: ((c1 >> 8) & 0xff) as i32,
+            line_width: ((c1 >> 16) & 0xff) as f32 * 2.0,
+            video_memory_offset: (c2 & 0xffff) as i32,
+            chactl: ((c2 >> 16) & 0x7) as i32 ^ chactl_xor,
+            dmactl: (((c2 >> 19) & 0x7) << 2) as i32,
+            charset_memory_offset: (c3 & 0xffff) as i32,
+            pmbase: ((c3 >> 16) & 0xff) as i32,
+        }
+    }
+}
+
+/// GTIA registers followed by antic memory, as uploaded to the data texture.
+struct Memory<'a> {
+    data: &'a [u8],
+    address_space: bool,
+}
+
+impl Memory<'_> {
+    fn byte(&self, offset: i32) -> i32 {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset))
+            .map_or(0, |&v| v as i32)
+    }
+
+    fn gtia_reg(&self, scan_line: i32, k: i32) -> i32 {
+        self.byte(scan_line * 32 + k)
+    }
+
+    fn gtia_reg4(&self, scan_line: i32, k: i32) -> [u32; 4] {
+        [0, 1, 2, 3].map(|i| self.gtia_reg(scan_line, k + i) as u32)
+    }
+
+    fn antic(&self, offset: i32) -> i32 {
+        self.byte(offset + GTIA_REGS_MEMORY as i32)
+    }
+
+    /// Byte `n` of screen data at `video_memory_offset`. With `MemoryMode::AddressSpace`
+    /// the memory scan counter wraps on 4K boundary, as in ANTIC.
+    fn screen(&self, video_memory_offset: i32, n: i32) -> i32 {
+        if self.address_space {
+            self.antic((video_memory_offset & 0xf000) | ((video_memory_offset + n) & 0xfff))
+        } else {
+            self.antic(video_memory_offset + n)
+        }
+    }
+
+    fn pm_dma(&self, pmbase: i32, pm_single_line: bool, area: i32, scan_line: i32) -> u32 {
+        let line = scan_line + 8;
+        let offset = if pm_single_line {
+            ((pmbase & 0xf8) << 8) + 0x300 + area * 0x100 + line
+        } else {
+            ((pmbase & 0xfc) << 8) + 0x180 + area * 0x80 + line / 2
+        };
+        self.antic(offset) as u32
+    }
+
+    fn grafp(&self, scan_line: i32, pmbase: i32, dmactl: i32) -> [u32; 4] {
+        let gractl = self.gtia_reg(scan_line, 0x1d);
+        if dmactl & 0x08 != 0 && gractl & 2 != 0 {
+            let single_line = dmactl & 0x10 != 0;
+            return [1, 2, 3, 4].map(|area| self.pm_dma(pmbase, single_line, area, scan_line));
+        }
+        self.gtia_reg4(scan_line, 0x0d)
+    }
+
+    fn grafm(&self, scan_line: i32, pmbase: i32, dmactl: i32) -> u32 {
+        let gractl = self.gtia_reg(scan_line, 0x1d);
+        if dmactl & 0x04 != 0 && gractl & 1 != 0 {
+            return self.pm_dma(pmbase, dmactl & 0x10 != 0, 0, scan_line);
+        }
+        self.gtia_reg(scan_line, 0x11) as u32
+    }
+}
+
+/// `ModeLineDescr::glyph_row` with shader types, -1 for blank rows.
+fn glyph_row(mode: i32, y: i32, c: i32, chactl: i32) -> i32 {
+    crate::glyph_row(mode as u8, chactl as u8, y as usize, c as u8).map_or(-1, |row| row as i32)
+}
+
+/// Value of hires pixel (ANTIC modes 2, 3 and 15 with GTIA mode 0) at `px_scrolled`,
+/// 1 for COLPF1 luminance.
+fn hires_pixel(memory: &Memory, line: &LineParams, px_scrolled: f32, y: i32) -> i32 {
+    let w = px_scrolled / 8.0;
+    let n = w as i32;
+    let bit_offs = 7 - ((w - n as f32) * 8.0) as u32;
+    if line.mode == 15 {
+        return (memory.screen(line.video_memory_offset, n) >> bit_offs) & 1;
+    }
+    let c = memory.screen(line.video_memory_offset, n);
+    let inv = (c >> 7) & (line.chactl >> 1) & 1;
+    let row = glyph_row(line.mode, y, c, line.chactl);
+    let mut byte = 0;
+    if row >= 0 && !(c & 0x80 != 0 && line.chactl & 1 != 0) {
+        byte = memory.antic(line.charset_memory_offset + (c & 0x7f) * 8 + row);
+    }
+    ((byte >> bit_offs) & 1) ^ inv
+}
+
+fn pm_pixels(px: f32, w: f32, size: [f32; 4], hpos: [f32; 4], data: [u32; 4]) -> [bool; 4] {
+    [0, 1, 2, 3].map(|i| {
+        if (hpos[i]..hpos[i] + size[i]).contains(&px) {
+            let bit = ((w - 0.001) * (1.0 - (px - hpos[i]) / size[i])) as u32;
+            (data[i] >> bit) & 1 > 0
+        } else {
+            false
+        }
+    })
+}
+
+/// Color of 16-color GTIA modes (PRIOR bits 6-7): returns (color_reg_index, color_reg).
+fn gtia_mode_color(
+    memory: &Memory,
+    scan_line: i32,
+    gtia_mode: i32,
+    value: i32,
+    colbk: i32,
+) -> (i32, i32) {
+    match gtia_mode {
+        1 => (0, value | colbk & 0xf0),
+        3 => {
+            let color_reg = value << 4;
+            if color_reg > 0 {
+                (0, color_reg | colbk & 0xf)
+            } else {
+                (0, color_reg)
+            }
+        }
+        _ => {
+            if value < 4 {
+                (value + 1, 0)
+            } else if value < 8 {
+                (0, memory.gtia_reg(scan_line, COLPM0 + value - 4))
+            } else {
+                (0, colbk)
+            }
+        }
+    }
+}
+
+pub(crate) fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let srgb = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
+
+/// Port of `fragment` from `antic.wgsl`: returns color and collisions of pixel at `x` (pixel center)
+/// and row `cy` of mode line.
+#[allow(clippy::nonminimal_bool)] // priority logic is kept in the same form as in the shader
+fn shade(
+    memory: &Memory,
+    config: &AnticConfig,
+    palette: &AtariPalette,
+    line: &LineParams,
+    x: f32,
+    cy: i32,
+) -> ([u8; 4], u64) {
+    let mode = line.mode;
+    let line_width = line.line_width;
+    let px = x - 192.0 + line_width / 2.0;
+    let px_scrolled = px + line.hscrol as f32;
+    let y = cy + line.line_voffset;
+    let scan_line = line.start_scan_line + cy;
+    let mut hires = false;
+
+    let hpos_offs = line_width / 2.0 - 256.0;
+    let hposp = memory
+        .gtia_reg4(scan_line, 0x00)
+        .map(|v| v as f32 * 2.0 + hpos_offs);
+    let hposm = memory
+        .gtia_reg4(scan_line, 0x04)
+        .map(|v| v as f32 * 2.0 + hpos_offs);
+
+    let mut color_reg_index = 0;
+    let prior = memory.gtia_reg(scan_line, 0x1b);
+    let gtia_mode = prior >> 6;
+    let mut color_reg = 0;
+    let colbk = memory.gtia_reg(scan_line, COLBK);
+
+    // byte offset and fractional position within byte for modes with `pixels` wide bytes
+    let split = |pixels: f32| {
+        let w = px_scrolled / pixels;
+        let n = w as i32;
+        (n, w - n as f32)
+    };
+
+    if mode == 0 || !(0.0..line_width).contains(&px) {
+    } else if mode == 2 || mode == 3 {
+        if gtia_mode == 0 {
+            color_reg_index = 3 - hires_pixel(memory, line, px_scrolled, y);
+            hires = true;
+        } else {
+            let (n, frac) = split(8.0);
+            let c = memory.screen(line.video_memory_offset, n);
+            let row = glyph_row(mode, y, c, line.chactl);
+            let mut byte = 0;
+            if row >= 0 && !(c & 0x80 != 0 && line.chactl & 1 != 0) {
+                byte = memory.antic(line.charset_memory_offset + (c & 0x7f) * 8 + row);
+            }
+            let bit_offs = 4 - (frac * 2.0) as u32 * 4;
+            let value = (byte >> bit_offs) & 0xf;
+            let (index, reg) = gtia_mode_color(memory, scan_line, gtia_mode, value, colbk);
+            color_reg_index = index;
+            color_reg = reg;
+        }
+    } else if mode == 4 || mode == 5 {
+        let (n, frac) = split(8.0);
+        let bit_offs = 6 - (frac * 4.0) as u32 * 2;
+        let c = memory.screen(line.video_memory_offset, n);
+        let inv = c >> 7;
+        let offs = (c & 0x7f) * 8 + glyph_row(mode, y, c, line.chactl);
+        let byte = memory.antic(line.charset_memory_offset + offs);
+        color_reg_index = (byte >> bit_offs) & 3;
+        if inv != 0 && color_reg_index == 3 {
+            color_reg_index = 4;
+        }
+    } else if mode == 6 || mode == 7 {
+        let (n, frac) = split(16.0);
+        let bit_offs = 7 - (frac * 8.0) as u32;
+        let c = memory.screen(line.video_memory_offset, n);
+        let cc = c >> 6;
+        let offs = (c & 0x3f) * 8 + glyph_row(mode, y, c, line.chactl);
+        let byte = memory.antic(line.charset_memory_offset + offs);
+        color_reg_index = if (byte >> bit_offs) & 1 > 0 {
+            cc + 1
+        } else {
+            0
+        };
+    } else if mode == 8 {
+        let (n, frac) = split(32.0);
+        let bit_offs = 6 - (frac * 4.0) as u32 * 2;
+        let byte = memory.screen(line.video_memory_offset, n);
+        color_reg_index = (byte >> bit_offs) & 3;
+    } else if mode == 9 {
+        let (n, frac) = split(32.0);
+        let bit_offs = 7 - (frac * 8.0) as u32;
+        let byte = memory.screen(line.video_memory_offset, n);
+        color_reg_index = (byte >> bit_offs) & 1;
+    } else if mode == 10 {
+        let (n, frac) = split(16.0);
+        let bit_offs = 6 - (frac * 4.0) as u32 * 2;
+        let byte = memory.screen(line.video_memory_offset, n);
+        color_reg_index = (byte >> bit_offs) & 3;
+    } else if mode == 11 || mode == 12 {
+        let (n, frac) = split(16.0);
+        let bit_offs = 7 - (frac * 8.0) as u32;
+        let byte = memory.screen(line.video_memory_offset, n);
+        color_reg_index = (byte >> bit_offs) & 1;
+    } else if mode == 13 || mode == 14 {
+        let (n, frac) = split(8.0);
+        let bit_offs = 6 - (frac * 4.0) as u32 * 2;
+        let byte = memory.screen(line.video_memory_offset, n);
+        color_reg_index = (byte >> bit_offs) & 3;
+    } else if mode == 15 {
+        if gtia_mode == 0 {
+            color_reg_index = 3 - hires_pixel(memory, line, px_scrolled, y);
+            hires = true;
+        } else {
+            let (n, frac) = split(8.0);
+            let byte = memory.screen(line.video_memory_offset, n);
+            let bit_offs = 4 - (frac * 2.0) as u32 * 4;
+            let value = (byte >> bit_offs) & 0xf;
+            let (index, reg) = gtia_mode_color(memory, scan_line, gtia_mode, value, colbk);
+            color_reg_index = index;
+            color_reg = reg;
+        }
+    }
+
+    let pri0 = prior & 1 > 0;
+    let pri1 = prior & 2 > 0;
+    let pri2 = prior & 4 > 0;
+    let pri3 = prior & 8 > 0;
+
+    let pri01 = pri0 || pri1;
+    let pri12 = pri1 || pri2;
+    let pri23 = pri2 || pri3;
+    let pri03 = pri0 || pri3;
+
+    // VDELAY: in two-line resolution delayed objects are updated on odd scan lines only
+    let pm_single_line = line.dmactl & 0x10 != 0;
+    let delayed = !pm_single_line && scan_line & 1 == 0 && scan_line > 0;
+    let vdelay = if delayed {
+        memory.gtia_reg(scan_line, 0x1c) as u32
+    } else {
+        0
+    };
+    let prev_scan_line = (scan_line - 1).max(0);
+
+    let mdelay = vdelay & 0xf;
+    let mdelay_mask = ((mdelay & 1) * 0x03)
+        | ((mdelay & 2) * 0x06)
+        | ((mdelay & 4) * 0x0c)
+        | ((mdelay & 8) * 0x18);
+    let grafm = (memory.grafm(scan_line, line.pmbase, line.dmactl) & !mdelay_mask)
+        | (memory.grafm(prev_scan_line, line.pmbase, line.dmactl) & mdelay_mask);
+
+    let missile_shift = [0, 2, 4, 6];
+    let mdata = missile_shift.map(|shift| grafm >> shift);
+    let sizem = memory.gtia_reg(scan_line, 0x0c) as u32;
+    let msize = missile_shift.map(|shift| (4 << ((sizem >> shift) & 3)) as f32);
+
+    let m = pm_pixels(px, 2.0, msize, hposm, mdata);
+    let [m0, m1, m2, m3] = m;
+
+    let p5 = prior & 0x10 > 0;
+
+    let psize = memory
+        .gtia_reg4(scan_line, 0x08)
+        .map(|size| (16 << (size & 3)) as f32);
+    let pdelay_mask = [4, 5, 6, 7].map(|bit| ((vdelay >> bit) & 1) * 0xff);
+    let grafp = memory.grafp(scan_line, line.pmbase, line.dmactl);
+    let prev_grafp = memory.grafp(prev_scan_line, line.pmbase, line.dmactl);
+    let data =
+        [0, 1, 2, 3].map(|i| (grafp[i] & !pdelay_mask[i]) | (prev_grafp[i] & pdelay_mask[i]));
+
+    let p = pm_pixels(px, 8.0, psize, hposp, data);
+    let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|i| p[i] || (!p5 && m[i]));
+
+    let pf0 = color_reg_index == 1;
+    let pf1 = !hires && color_reg_index == 2;
+    let pf2 = hires || color_reg_index == 3;
+    let pf3 = color_reg_index == 4 || p5 && (m0 || m1 || m2 || m3);
+
+    let p01 = p0 || p1;
+    let p23 = p2 || p3;
+    let pf01 = pf0 || pf1;
+    let pf23 = pf2 || pf3;
+
+    let multi = prior & 0x20 > 0;
+
+    let sp0 = p0 && !(pf01 && pri23) && !(pri2 && pf23);
+    let sp1 = p1 && !(pf01 && pri23) && !(pri2 && pf23) && (!p0 || multi);
+    let sp2 = p2 && !p01 && !(pf23 && pri12) && !(pf01 && !pri0);
+    let sp3 = p3 && !p01 && !(pf23 && pri12) && !(pf01 && !pri0) && (!p2 || multi);
+    let sf3 = pf3 && !(p23 && pri03) && !(p01 && !pri2);
+    let sf0 = pf0 && !(p23 && pri0) && !(p01 && pri01) && !sf3;
+    let sf1 = pf1 && !(p23 && pri0) && !(p01 && pri01) && !sf3;
+    let sf2 = pf2 && !(p23 && pri03) && !(p01 && !pri2) && !sf3;
+    let sb = !p01 && !p23 && !pf01 && !pf23;
+
+    for (i, selected) in [sp0, sp1, sp2, sp3].into_iter().enumerate() {
+        if selected {
+            color_reg |= memory.gtia_reg(scan_line, COLPM0 + i as i32);
+        }
+    }
+    for (i, selected) in [sf0, sf1, sf2, sf3].into_iter().enumerate() {
+        if selected {
+            color_reg |= memory.gtia_reg(scan_line, COLPF0 + i as i32);
+        }
+    }
+    if sb && gtia_mode == 0 {
+        color_reg |= colbk;
+    }
+
+    if hires && color_reg_index == 2 {
+        color_reg = (color_reg & 0xf0) | (memory.gtia_reg(scan_line, COLPF0 + 1) & 0xf);
+    }
+
+    let pf_bits = pf0 as u64 | (pf1 as u64) << 1 | (pf2 as u64) << 2 | (pf3 as u64) << 3;
+    let player_bits = p
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &v)| bits | (v as u64) << i);
+
+    let mut collisions = 0;
+    for (i, (&m, &p)) in m.iter().zip(p.iter()).enumerate() {
+        if m {
+            collisions |= pf_bits << (i * 4);
+            collisions |= player_bits << (32 + i * 4);
+        }
+        if p {
+            collisions |= pf_bits << (16 + i * 4);
+            collisions |= (player_bits & !(1 << i)) << (48 + i * 4);
+        }
+    }
+
+    // collisions region: 0 - playfield, 1 - visible line (no HBLANK), 2 - full line
+    let in_playfield = (0.0..line_width).contains(&px);
+    let in_visible = (4.0..380.0).contains(&x);
+    let detect = match config.collisions_region {
+        0 => in_playfield,
+        1 => in_visible,
+        2 => true,
+        _ => false,
+    };
+    if !detect {
+        collisions = 0;
+    }
+
+    let mut color = palette.0[color_reg as usize & 0xff];
+    // NTSC artifacts of hires playfield not covered by players
+    if hires && sf2 && config.artifacts != ArtifactMode::None as i32 {
+        let pixel_val = 3 - color_reg_index;
+        // pixels outside of playfield continue the pattern
+        let neighbour = |dx: f32| {
+            if (0.0..line_width).contains(&(px + dx)) {
+                hires_pixel(memory, line, px_scrolled + dx, y)
+            } else {
+                pixel_val
+            }
+        };
+        let pixels = [neighbour(-1.0), pixel_val, neighbour(1.0)];
+        let colpf1 = memory.gtia_reg(scan_line, COLPF0 + 1);
+        let colpf2 = memory.gtia_reg(scan_line, COLPF0 + 2);
+        if config.artifacts == ArtifactMode::Lookup as i32 {
+            if let Some(reg) = artifacts::lookup_color(x as i32, pixels, colpf1, colpf2) {
+                color = palette.0[reg as usize];
+            }
+        } else {
+            let pixel_color = |v: i32| {
+                let reg = if v == 1 {
+                    (colpf2 & 0xf0) | (colpf1 & 0xf)
+                } else {
+                    colpf2
+                };
+                palette.0[reg as usize]
+            };
+            let colors = pixels.map(pixel_color);
+            color = artifacts::signal_color(x as i32, colors);
+        }
+    }
+    // scan lines outside of visible area of the video standard
+    if scan_line < config.first_visible_line || scan_line >= config.last_visible_line {
+        color = [0.0, 0.0, 0.0, 1.0];
+    }
+    if scan_line == config.debug_scan_line {
+        let alpha = 0.5;
+        for (i, c) in color.iter_mut().take(3).enumerate() {
+            let red = if i == 0 { 1.0 } else { 0.0 };
+            *c = alpha * red + (1.0 - alpha) * *c;
+        }
+        color[3] = 1.0;
+    }
+    let color = [
+        linear_to_srgb(color[0]),
+        linear_to_srgb(color[1]),
+        linear_to_srgb(color[2]),
+        (color[3].clamp(0.0, 1.0) * 255.0).round() as u8,
+    ];
+    (color, collisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Frame};
+    use crate::{
+        pal_blending::blend, AnticData, ArtifactMode, CollisionsRegion, GTIARegs, MemoryMode,
+        ModeLineDescr,
+    };
+
+    fn gtia_regs() -> GTIARegs {
+        GTIARegs {
+            col: [0x00, 0x00, 0x00, 0x00, 0x28, 0xca, 0x94, 0x46, 0x00],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mode2_pixels() {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        for scan_line in 0..240 {
+            antic_data.set_gtia_regs(scan_line, &gtia_regs());
+        }
+        // character 1 at $4000, glyph row 0 = %10000000 in charset at $e000
+        antic_data.set_atari_memory(0x4000, &[1]);
+        antic_data.set_atari_memory(0xe008, &[0x80]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 2,
+            scan_line: 8,
+            width: 320,
+            height: 8,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            chbase: 0xe0,
+            ..Default::default()
+        });
+        antic_data.config.debug_scan_line = -1;
+        let mut frame = Frame::default();
+        render(&antic_data, &mut frame);
+
+        let palette = antic_data.inner.read().palette;
+        let color = |reg: usize| {
+            let c = palette.0[reg];
+            [c[0], c[1], c[2]].map(super::linear_to_srgb)
+        };
+        // playfield starts at x = 32; first hires pixel uses luminance of COLPF1 on COLPF2
+        assert_eq!(frame.pixel(32, 0)[..3], color(0x9a));
+        assert_eq!(frame.pixel(33, 0)[..3], color(0x94));
+        // border
+        assert_eq!(frame.pixel(0, 0)[..3], color(0x00));
+        // scan lines not covered by mode lines are untouched (paused line is drawn below)
+        assert_eq!(frame.pixel(0, 20), [128; 4]);
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        antic_data.set_gtia_regs(0, &gtia_regs());
+        // alternating pixels followed by lit ones
+        antic_data.set_atari_memory(0x4000, &[0xaa, 0xaa, 0xff, 0xff]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 15,
+            scan_line: 8,
+            width: 320,
+            height: 1,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            ..Default::default()
+        });
+        antic_data.config.debug_scan_line = -1;
+        let render_with = |antic_data: &mut AnticData, mode: ArtifactMode| {
+            antic_data.config.artifacts = mode as i32;
+            let mut frame = Frame::default();
+            render(antic_data, &mut frame);
+            frame
+        };
+        let plain = render_with(&mut antic_data, ArtifactMode::None);
+        let lookup = render_with(&mut antic_data, ArtifactMode::Lookup);
+        let signal = render_with(&mut antic_data, ArtifactMode::Signal);
+
+        let palette = antic_data.inner.read().palette;
+        let color = |reg: usize| {
+            let c = palette.0[reg];
+            [c[0], c[1], c[2]].map(super::linear_to_srgb)
+        };
+        // left pixels of color clocks lit: orange, luminance between COLPF2 and COLPF1
+        assert_eq!(lookup.pixel(32, 0)[..3], color(0x26));
+        assert_eq!(lookup.pixel(33, 0)[..3], color(0x26));
+        assert!(signal.pixel(32, 0) != plain.pixel(32, 0));
+        // solid areas and border are not affected
+        for frame in [&lookup, &signal] {
+            assert_eq!(frame.pixel(60, 0), plain.pixel(60, 0));
+            assert_eq!(frame.pixel(0, 0), plain.pixel(0, 0));
+        }
+    }
+
+    #[test]
+    fn test_pal_blending() {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        // red and blue background on alternate scan lines
+        for (scan_line, colbk) in [(0, 0x34), (1, 0x84), (2, 0x34)] {
+            let mut regs = gtia_regs();
+            regs.col[8] = colbk;
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 0,
+            scan_line: 8,
+            height: 3,
+            ..Default::default()
+        });
+        antic_data.config.debug_scan_line = -1;
+        let mut plain = Frame::default();
+        render(&antic_data, &mut plain);
+        antic_data.config.pal_blending = 1;
+        let mut blended = Frame::default();
+        render(&antic_data, &mut blended);
+
+        // the first scan line has no previous one
+        assert_eq!(blended.pixel(100, 0), plain.pixel(100, 0));
+        let srgb = |y| {
+            let pixel = plain.pixel(100, y);
+            [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0)
+        };
+        for y in [1, 2] {
+            let expected = blend(srgb(y), srgb(y - 1)).map(|c| (c * 255.0).round() as u8);
+            assert_eq!(blended.pixel(100, y)[..3], expected);
+            assert!(blended.pixel(100, y) != plain.pixel(100, y));
+        }
+        // scan lines not covered by mode lines are untouched
+        assert_eq!(blended.pixel(100, 20), [128; 4]);
+    }
+
+    #[test]
+    fn test_player_playfield_collision() {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        let mut regs = gtia_regs();
+        regs.hposp[0] = 0x80;
+        regs.grafp[0] = 0xff;
+        antic_data.set_gtia_regs(0, &regs);
+        antic_data.set_atari_memory(0x4000, &[0xff; 40]);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 15,
+            scan_line: 8,
+            width: 320,
+            height: 1,
+            n_bytes: 40,
+            video_memory_offset: 0x4000,
+            ..Default::default()
+        });
+        let mut frame = Frame::default();
+        render(&antic_data, &mut frame);
+        // player 0 on hires playfield (PF2)
+        assert_eq!(frame.collisions[0], 0x4 << 16);
+    }
+
+    #[test]
+    fn test_collisions_region() {
+        // players overlapping left of narrow playfield, in the visible area
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        let mut regs = gtia_regs();
+        regs.hposp[0] = 0x30;
+        regs.hposp[1] = 0x30;
+        regs.grafp[0] = 0xff;
+        regs.grafp[1] = 0xff;
+        antic_data.set_gtia_regs(0, &regs);
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 15,
+            scan_line: 8,
+            width: 256,
+            height: 1,
+            n_bytes: 32,
+            video_memory_offset: 0x4000,
+            ..Default::default()
+        });
+        let player_collisions = |antic_data: &AnticData| {
+            let mut frame = Frame::default();
+            render(antic_data, &mut frame);
+            frame.collisions[0] >> 48
+        };
+        assert_eq!(player_collisions(&antic_data), 0x12);
+        antic_data.config.collisions_region = CollisionsRegion::Playfield as i32;
+        assert_eq!(player_collisions(&antic_data), 0);
+    }
+
+    const PMBASE: usize = 0x3000;
+
+    /// Player 0 at x = 192, missile 0 at x = 224.
+    fn pm_regs(gractl: u8) -> GTIARegs {
+        GTIARegs {
+            hposp: [0x80, 0, 0, 0],
+            hposm: [0x90, 0, 0, 0],
+            col: [0x38, 0, 0, 0, 0, 0, 0, 0, 0],
+            gractl,
+            ..Default::default()
+        }
+    }
+
+    /// Blank lines with `regs` on the first 16 scan lines.
+    fn pm_antic_data(dmactl: u8, regs: GTIARegs) -> AnticData {
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        for scan_line in 0..16 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 0,
+            scan_line: 8,
+            width: 320,
+            height: 16,
+            pmbase: (PMBASE >> 8) as u8,
+            dmactl,
+            ..Default::default()
+        });
+        antic_data
+    }
+
+    /// Scan lines with a pixel of color other than background at `x`.
+    fn drawn_lines(antic_data: &AnticData, x: usize) -> Vec<usize> {
+        let mut frame = Frame::default();
+        render(antic_data, &mut frame);
+        (0..16)
+            .filter(|&y| frame.pixel(x, y) != frame.pixel(16, y))
+            .collect()
+    }
+
+    #[test]
+    fn test_pm_dma() {
+        let (player, missile) = (193, 225);
+
+        // single line resolution: one byte per scan line, players from PMBASE + $400
+        let mut antic_data = pm_antic_data(0x3e, pm_regs(3));
+        antic_data.set_atari_memory(PMBASE + 0x400 + 8 + 3, &[0xff]);
+        antic_data.set_atari_memory(PMBASE + 0x300 + 8 + 5, &[0x03]);
+        assert_eq!(drawn_lines(&antic_data, player), [3]);
+        assert_eq!(drawn_lines(&antic_data, missile), [5]);
+
+        // two line resolution: byte repeated on two scan lines, players from PMBASE + $200
+        let mut antic_data = pm_antic_data(0x2e, pm_regs(3));
+        antic_data.set_atari_memory(PMBASE + 0x200 + (8 + 4) / 2, &[0xff]);
+        antic_data.set_atari_memory(PMBASE + 0x180 + (8 + 6) / 2, &[0x03]);
+        assert_eq!(drawn_lines(&antic_data, player), [4, 5]);
+        assert_eq!(drawn_lines(&antic_data, missile), [6, 7]);
+
+        // GRACTL and DMACTL disable DMA of players and missiles separately
+        for (dmactl, gractl) in [(0x3e, 0), (0x36, 1), (0x3e, 1)] {
+            let mut antic_data = pm_antic_data(dmactl, pm_regs(gractl));
+            antic_data.set_atari_memory(PMBASE + 0x400 + 8 + 3, &[0xff]);
+            assert_eq!(drawn_lines(&antic_data, player), [] as [usize; 0]);
+        }
+        let mut antic_data = pm_antic_data(0x3a, pm_regs(3));
+        antic_data.set_atari_memory(PMBASE + 0x300 + 8 + 5, &[0x03]);
+        assert_eq!(drawn_lines(&antic_data, missile), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn test_vdelay() {
+        let player = 193;
+        let delayed = GTIARegs {
+            vdelay: 0x10,
+            ..pm_regs(3)
+        };
+        // two line resolution data on scan lines 2 and 3
+        let mut antic_data = pm_antic_data(0x2e, pm_regs(3));
+        antic_data.set_atari_memory(PMBASE + 0x200 + (8 + 2) / 2, &[0xff]);
+        assert_eq!(drawn_lines(&antic_data, player), [2, 3]);
+
+        // delayed player keeps data of the previous (odd) scan line on even ones
+        let mut antic_data = pm_antic_data(0x2e, delayed);
+        antic_data.set_atari_memory(PMBASE + 0x200 + (8 + 2) / 2, &[0xff]);
+        assert_eq!(drawn_lines(&antic_data, player), [3, 4]);
+
+        // VDELAY has no effect in single line resolution
+        let mut antic_data = pm_antic_data(0x3e, delayed);
+        antic_data.set_atari_memory(PMBASE + 0x400 + 8 + 2, &[0xff, 0xff]);
+        assert_eq!(drawn_lines(&antic_data, player), [2, 3]);
+    }
+}
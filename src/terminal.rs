@@ -0,0 +1,280 @@
+//! Terminal output of rendered frames: ANSI truecolor half-block characters or sixel graphics.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    software::{self, Frame, FRAME_HEIGHT, FRAME_WIDTH},
+    AnticData,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalFormat {
+    /// `▀` characters with truecolor foreground (upper pixel) and background (lower pixel).
+    HalfBlocks,
+    /// DEC sixel graphics.
+    Sixel,
+}
+
+impl TerminalFormat {
+    /// Guesses format supported by the terminal from `TERM` and `TERM_PROGRAM` variables.
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default().to_ascii_lowercase();
+        let (term, program) = (var("TERM"), var("TERM_PROGRAM"));
+        let sixel_terms = ["sixel", "mlterm", "foot", "contour", "yaft"];
+        let sixel_programs = ["wezterm", "iterm.app", "mintty"];
+        if sixel_terms.iter().any(|t| term.contains(t))
+            || sixel_programs.iter().any(|p| program.contains(p))
+        {
+            TerminalFormat::Sixel
+        } else {
+            TerminalFormat::HalfBlocks
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerminalOptions {
+    /// Rendered rectangle of the frame: x, y, width, height.
+    pub crop: (usize, usize, usize, usize),
+    /// Output pixels per frame pixel. Values below 1 average frame pixels,
+    /// e.g. 0.5 fits the whole frame in 192 columns with half-blocks.
+    pub scale: f32,
+}
+
+impl Default for TerminalOptions {
+    fn default() -> Self {
+        Self {
+            crop: (0, 0, FRAME_WIDTH, FRAME_HEIGHT),
+            scale: 0.5,
+        }
+    }
+}
+
+/// Renders `antic_data` with the software renderer and returns escape sequences displaying it.
+pub fn render(antic_data: &AnticData, format: TerminalFormat, options: &TerminalOptions) -> String {
+    let mut frame = Frame::default();
+    software::render(antic_data, &mut frame);
+    match format {
+        TerminalFormat::HalfBlocks => half_blocks(&frame, options),
+        TerminalFormat::Sixel => sixel(&frame, options),
+    }
+}
+
+/// Crops and scales `frame`, returning width, height and RGB pixels.
+fn resample(frame: &Frame, options: &TerminalOptions) -> (usize, usize, Vec<[u8; 3]>) {
+    let (x0, y0, width, height) = options.crop;
+    let width = width.min(FRAME_WIDTH.saturating_sub(x0));
+    let height = height.min(FRAME_HEIGHT.saturating_sub(y0));
+    let scale = options.scale.max(f32::EPSILON);
+    let out_width = ((width as f32 * scale).round() as usize).max(1);
+    let out_height = ((height as f32 * scale).round() as usize).max(1);
+    // source range of output pixel `i`, at least one pixel wide
+    let range = |i: usize, len: usize, out_len: usize| {
+        let start = i * len / out_len;
+        let end = ((i + 1) * len / out_len).max(start + 1);
+        start..end.min(len)
+    };
+
+    let mut pixels = Vec::with_capacity(out_width * out_height);
+    for oy in 0..out_height {
+        for ox in 0..out_width {
+            let mut sum = [0u32; 3];
+            let mut count = 0;
+            for y in range(oy, height, out_height) {
+                for x in range(ox, width, out_width) {
+                    let pixel = frame.pixel(x0 + x, y0 + y);
+                    for (sum, v) in sum.iter_mut().zip(pixel) {
+                        *sum += v as u32;
+                    }
+                    count += 1;
+                }
+            }
+            pixels.push(sum.map(|v| ((v + count / 2) / count.max(1)) as u8));
+        }
+    }
+    (out_width, out_height, pixels)
+}
+
+/// Frame as lines of `▀` characters, each covering two rows of pixels.
+pub fn half_blocks(frame: &Frame, options: &TerminalOptions) -> String {
+    let (width, height, pixels) = resample(frame, options);
+    let mut out = String::new();
+    for y in (0..height).step_by(2) {
+        let mut colors = None;
+        for x in 0..width {
+            let upper = pixels[y * width + x];
+            // odd height: the last row is drawn over black
+            let lower = if y + 1 < height {
+                pixels[(y + 1) * width + x]
+            } else {
+                [0, 0, 0]
+            };
+            if colors != Some((upper, lower)) {
+                let ([fr, fg, fb], [br, bg, bb]) = (upper, lower);
+                write!(
+                    out,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                    fr, fg, fb, br, bg, bb
+                )
+                .unwrap();
+                colors = Some((upper, lower));
+            }
+            out.push('▀');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Frame as sixel graphics. Frames with more than 256 colors (possible when downscaled)
+/// are quantized to 6x7x6 color cube.
+pub fn sixel(frame: &Frame, options: &TerminalOptions) -> String {
+    let (width, height, pixels) = resample(frame, options);
+
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut lookup: HashMap<[u8; 3], usize> = HashMap::new();
+    for pixel in &pixels {
+        if !lookup.contains_key(pixel) && lookup.len() <= 256 {
+            lookup.insert(*pixel, palette.len());
+            palette.push(*pixel);
+        }
+    }
+    let indices: Vec<usize> = if palette.len() <= 256 {
+        pixels.iter().map(|pixel| lookup[pixel]).collect()
+    } else {
+        palette = (0..6 * 7 * 6)
+            .map(|i| [i / 42 * 51, i / 6 % 7 * 255 / 6, i % 6 * 51].map(|v| v as u8))
+            .collect();
+        pixels
+            .iter()
+            .map(|[r, g, b]| {
+                let (r, g, b) = (*r as usize, *g as usize, *b as usize);
+                (r + 25) / 51 * 42 + (g * 6 + 127) / 255 * 6 + (b + 25) / 51
+            })
+            .collect()
+    };
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for (index, [r, g, b]) in palette.iter().enumerate() {
+        let percent = |v: &u8| (*v as u32 * 100 + 127) / 255;
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            index,
+            percent(r),
+            percent(g),
+            percent(b)
+        )
+        .unwrap();
+    }
+    for band in (0..height).step_by(6) {
+        let rows = band..(band + 6).min(height);
+        let mut band_colors: Vec<usize> = rows
+            .clone()
+            .flat_map(|y| indices[y * width..(y + 1) * width].iter().cloned())
+            .collect();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+        for (n, &color) in band_colors.iter().enumerate() {
+            if n > 0 {
+                out.push('$');
+            }
+            write!(out, "#{}", color).unwrap();
+            let sixels = (0..width).map(|x| {
+                rows.clone()
+                    .filter(|&y| indices[y * width + x] == color)
+                    .fold(0, |bits, y| bits | 1 << (y - band))
+            });
+            push_run_length(&mut out, sixels);
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+/// Appends sixel characters of `bits`, with repeated characters compressed as `!count`.
+/// Trailing empty sixels are omitted.
+fn push_run_length(out: &mut String, bits: impl Iterator<Item = u8>) {
+    let mut run: Option<(u8, usize)> = None;
+    let flush = |out: &mut String, (bits, count): (u8, usize)| {
+        let c = (63 + bits) as char;
+        if count > 3 {
+            write!(out, "!{}{}", count, c).unwrap();
+        } else {
+            for _ in 0..count {
+                out.push(c);
+            }
+        }
+    };
+    for bits in bits {
+        run = match run {
+            Some((prev, count)) if prev == bits => Some((prev, count + 1)),
+            Some(prev) => {
+                flush(out, prev);
+                Some((bits, 1))
+            }
+            None => Some((bits, 1)),
+        };
+    }
+    if let Some(run) = run.filter(|(bits, _)| *bits > 0) {
+        flush(out, run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{half_blocks, sixel, TerminalOptions};
+    use crate::software::{Frame, FRAME_WIDTH};
+
+    fn frame() -> Frame {
+        let mut frame = Frame::default();
+        // 2x2 block at (0, 0): red, green / blue, white
+        for (x, y, color) in [
+            (0, 0, [255, 0, 0, 255]),
+            (1, 0, [0, 255, 0, 255]),
+            (0, 1, [0, 0, 255, 255]),
+            (1, 1, [255, 255, 255, 255]),
+        ] {
+            let offset = (y * FRAME_WIDTH + x) * 4;
+            frame.pixels[offset..offset + 4].copy_from_slice(&color);
+        }
+        frame
+    }
+
+    #[test]
+    fn test_half_blocks() {
+        let options = TerminalOptions {
+            crop: (0, 0, 2, 2),
+            scale: 1.0,
+        };
+        assert_eq!(
+            half_blocks(&frame(), &options),
+            "\x1b[38;2;255;0;0;48;2;0;0;255m▀\x1b[38;2;0;255;0;48;2;255;255;255m▀\x1b[0m\n"
+        );
+
+        let options = TerminalOptions {
+            crop: (0, 0, 2, 2),
+            scale: 0.5,
+        };
+        assert_eq!(
+            half_blocks(&frame(), &options),
+            "\x1b[38;2;128;128;128;48;2;0;0;0m▀\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_sixel() {
+        let options = TerminalOptions {
+            crop: (0, 0, 8, 2),
+            scale: 1.0,
+        };
+        let expected = concat!(
+            "\x1bPq\"1;1;8;2",
+            "#0;2;100;0;0#1;2;0;100;0#2;2;50;50;50#3;2;0;0;100#4;2;100;100;100",
+            "#0@$#1?@$#2??!6B$#3A$#4?A-",
+            "\x1b\\"
+        );
+        assert_eq!(sixel(&frame(), &options), expected);
+    }
+}
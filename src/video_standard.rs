@@ -0,0 +1,144 @@
+use std::ops::Range;
+
+use crate::display_list::{FIRST_SCAN_LINE, LAST_SCAN_LINE};
+
+/// Video standard of emulated machine: selects frame timing, visible scan lines and palette.
+///
+/// Both standards are rendered to the same `SCAN_LINES` lines of the main image,
+/// scan lines outside of `visible_scan_lines` are black.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VideoStandard {
+    #[default]
+    Pal,
+    Ntsc,
+}
+
+impl VideoStandard {
+    /// Number of scan lines of a whole frame, including vertical blank.
+    pub fn scan_lines_per_frame(&self) -> usize {
+        match self {
+            VideoStandard::Pal => 312,
+            VideoStandard::Ntsc => 262,
+        }
+    }
+
+    /// Number of scan lines shown on a TV above and below the standard 192 line playfield
+    /// (scan lines 32..224).
+    pub fn overscan_lines(&self) -> usize {
+        match self {
+            VideoStandard::Pal => 24,
+            VideoStandard::Ntsc => 16,
+        }
+    }
+
+    /// ANTIC scan lines shown on a TV: the standard playfield with overscan lines,
+    /// 240 lines for PAL and 224 for NTSC.
+    pub fn visible_scan_lines(&self) -> Range<usize> {
+        let overscan = self.overscan_lines();
+        (32 - overscan).max(FIRST_SCAN_LINE)..(224 + overscan).min(LAST_SCAN_LINE)
+    }
+
+    /// Value of GTIA PAL register ($D014) read by programs to detect the video standard.
+    pub fn pal_register(&self) -> u8 {
+        match self {
+            VideoStandard::Pal => 0x01,
+            VideoStandard::Ntsc => 0x0f,
+        }
+    }
+
+    /// CPU clock frequency in Hz.
+    pub fn cpu_clock(&self) -> f64 {
+        match self {
+            VideoStandard::Pal => 1_773_447.0,
+            VideoStandard::Ntsc => 1_789_772.5,
+        }
+    }
+
+    /// CPU cycles of a single frame, 114 per scan line.
+    pub fn cycles_per_frame(&self) -> usize {
+        114 * self.scan_lines_per_frame()
+    }
+
+    /// Frames per second, about 49.86 for PAL and 59.92 for NTSC.
+    pub fn frame_rate(&self) -> f64 {
+        self.cpu_clock() / self.cycles_per_frame() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VideoStandard;
+    use crate::{
+        software::{render, Frame},
+        AnticData, AtariPalette, GTIARegs, MemoryMode, ModeLineDescr, Recorder,
+    };
+
+    #[test]
+    fn test_frame_rate() {
+        assert!((VideoStandard::Pal.frame_rate() - 49.86).abs() < 0.01);
+        assert!((VideoStandard::Ntsc.frame_rate() - 59.92).abs() < 0.01);
+        let recording = Recorder::for_standard(VideoStandard::Ntsc).finish();
+        assert!((recording.fps - 59.92).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_palette() {
+        let mut antic_data = AnticData::headless(MemoryMode::Reserved);
+        antic_data.set_video_standard(VideoStandard::Ntsc);
+        let ntsc = AtariPalette::for_standard(VideoStandard::Ntsc);
+        assert!(antic_data.inner.read().palette == ntsc);
+
+        // custom palette is kept
+        let mut custom = ntsc;
+        custom.0[0x0e] = [1.0, 0.0, 0.0, 1.0];
+        antic_data.inner.write().palette = custom;
+        antic_data.set_video_standard(VideoStandard::Pal);
+        assert!(antic_data.inner.read().palette == custom);
+        assert_eq!(antic_data.video_standard(), VideoStandard::Pal);
+    }
+
+    #[test]
+    fn test_visible_scan_lines() {
+        assert_eq!(VideoStandard::Pal.visible_scan_lines(), 8..248);
+        assert_eq!(VideoStandard::Ntsc.visible_scan_lines(), 16..240);
+        assert_eq!(VideoStandard::Ntsc.visible_scan_lines().len(), 192 + 2 * 16);
+        assert_eq!(VideoStandard::Pal.pal_register() & 0x0e, 0);
+        assert_eq!(VideoStandard::Ntsc.pal_register() & 0x0e, 0x0e);
+
+        let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+        antic_data.config.debug_scan_line = -1;
+        // overlapping players 0 and 1 on background
+        let regs = GTIARegs {
+            hposp: [0x80, 0x80, 0, 0],
+            grafp: [0xff, 0xff, 0, 0],
+            col: [0x38, 0x38, 0, 0, 0, 0, 0, 0, 0x46],
+            ..Default::default()
+        };
+        for scan_line in 0..240 {
+            antic_data.set_gtia_regs(scan_line, &regs);
+        }
+        antic_data.insert_mode_line(&ModeLineDescr {
+            mode: 0,
+            scan_line: 8,
+            height: 240,
+            ..Default::default()
+        });
+        let render_standard = |antic_data: &mut AnticData, standard| {
+            antic_data.set_video_standard(standard);
+            let mut frame = Frame::default();
+            render(antic_data, &mut frame);
+            frame
+        };
+
+        let pal = render_standard(&mut antic_data, VideoStandard::Pal);
+        let ntsc = render_standard(&mut antic_data, VideoStandard::Ntsc);
+        let black = [0, 0, 0, 255];
+        for y in 0..240 {
+            let visible = (8..232).contains(&y);
+            assert_ne!(pal.pixel(10, y), black);
+            assert_eq!(ntsc.pixel(10, y) == black, !visible, "row {}", y);
+            // GTIA still detects collisions on lines not shown
+            assert_ne!(ntsc.collisions[y], 0);
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! Golden image tests: fixtures are rendered with the software renderer and compared
+//! against PNG files in `tests/golden`. Run with `UPDATE_GOLDEN=1` to (re)record them.
+//!
+//! `gpu_*` tests render the same fixtures with the shader and compare them against the same
+//! files. They need a GPU, run them with `cargo test -- --ignored`. Golden images are recorded
+//! from the software renderer and CI has no GPU, so CI only covers the software path.
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use bevy_atari_antic::{
+    render_offscreen,
+    software::{self, Frame, FRAME_HEIGHT, FRAME_WIDTH},
+    AnticData, AnticRegs, ArtifactMode, DisplayList, GTIARegs, MemoryMode, ModeLineDescr, Snapshot,
+};
+
+/// Maximum difference of a color component rendered on GPU from the golden image.
+const GPU_TOLERANCE: u8 = 1;
+
+const CHARSET: usize = 0xe000;
+const SCREEN: usize = 0x4000;
+const PM_AREA: usize = 0x3000;
+
+fn gtia_regs() -> GTIARegs {
+    GTIARegs {
+        // COLPM0-3, COLPF0-3, COLBK
+        col: [0x38, 0x88, 0xc8, 0x1a, 0x26, 0x0e, 0x94, 0x56, 0x02],
+        ..Default::default()
+    }
+}
+
+/// Address space with deterministic charset and screen data and default GTIA registers
+/// set on every scan line.
+fn antic_data() -> AnticData {
+    let mut antic_data = AnticData::headless(MemoryMode::AddressSpace);
+    antic_data.config.debug_scan_line = -1;
+    let charset: Vec<u8> = (0..1024)
+        .map(|i| {
+            let (c, row) = (i / 8, i % 8);
+            ((c * 0x35) ^ (row * 0x1b) ^ (row << 4)) as u8
+        })
+        .collect();
+    antic_data.set_atari_memory(CHARSET, &charset);
+    let screen: Vec<u8> = (0..0x1000).map(|i| (i * 7 + i / 48) as u8).collect();
+    antic_data.set_atari_memory(SCREEN, &screen);
+    set_regs(&mut antic_data, 0..240, |_| {});
+    antic_data
+}
+
+fn set_regs(
+    antic_data: &mut AnticData,
+    scan_lines: std::ops::Range<usize>,
+    f: impl Fn(&mut GTIARegs),
+) {
+    for scan_line in scan_lines {
+        let mut regs = gtia_regs();
+        f(&mut regs);
+        antic_data.set_gtia_regs(scan_line, &regs);
+    }
+}
+
+fn mode_line(
+    mode: u8,
+    scan_line: usize,
+    height: usize,
+    video_memory_offset: usize,
+) -> ModeLineDescr {
+    ModeLineDescr {
+        mode,
+        scan_line: scan_line + 8,
+        width: 320,
+        height,
+        video_memory_offset,
+        chbase: (CHARSET >> 8) as u8,
+        pmbase: (PM_AREA >> 8) as u8,
+        ..Default::default()
+    }
+}
+
+/// Players and missiles overlapping each other and playfield.
+fn set_pm_regs(regs: &mut GTIARegs) {
+    regs.hposp = [0x50, 0x58, 0x80, 0x88];
+    regs.hposm = [0x54, 0x5c, 0x84, 0x8c];
+    regs.sizep = [0, 1, 0, 3];
+    regs.sizem = 0b11100100;
+    regs.grafp = [0xff, 0xf0, 0x3c, 0xaa];
+    regs.grafm = 0xff;
+}
+
+/// Playfield of mode 13 lines, showing all four colors, on `scan_lines`.
+fn playfield(antic_data: &mut AnticData, scan_lines: std::ops::Range<usize>) {
+    antic_data.set_atari_memory(0x5000, &[0x1b; 40]);
+    for scan_line in scan_lines.step_by(2) {
+        antic_data.insert_mode_line(&mode_line(13, scan_line, 2, 0x5000));
+    }
+}
+
+fn antic_modes() -> AnticData {
+    let antic_data = antic_data();
+    let heights = [8, 10, 8, 16, 8, 16, 8, 4, 4, 2, 1, 2, 1, 1];
+    let mut scan_line = 0;
+    for (mode, height) in (2..=15).zip(heights) {
+        let video_memory_offset = SCREEN + mode as usize * 0x80;
+        antic_data.insert_mode_line(&mode_line(mode, scan_line, height, video_memory_offset));
+        scan_line += height;
+    }
+    // character modes with CHACTL blank and reflect
+    for mode in 2..=7 {
+        let height = heights[mode as usize - 2];
+        antic_data.insert_mode_line(&ModeLineDescr {
+            chactl: 5,
+            ..mode_line(mode, scan_line, height, SCREEN + 0x800)
+        });
+        scan_line += height;
+    }
+    antic_data
+}
+
+fn gtia_modes() -> AnticData {
+    let mut antic_data = antic_data();
+    for (i, gtia_mode) in (0..4).enumerate() {
+        let first = i * 40;
+        set_regs(&mut antic_data, first..first + 40, |regs| {
+            regs.prior = gtia_mode << 6;
+            regs.col[8] = 0x44;
+        });
+        for scan_line in first..first + 32 {
+            antic_data.insert_mode_line(&mode_line(15, scan_line, 1, SCREEN + scan_line * 40));
+        }
+        antic_data.insert_mode_line(&mode_line(2, first + 32, 8, SCREEN));
+    }
+    antic_data
+}
+
+fn priorities() -> AnticData {
+    let mut antic_data = antic_data();
+    for (i, prior) in [0, 1, 2, 4, 8].into_iter().enumerate() {
+        let first = i * 40;
+        set_regs(&mut antic_data, first..first + 40, |regs| {
+            set_pm_regs(regs);
+            regs.prior = prior;
+        });
+    }
+    playfield(&mut antic_data, 0..200);
+    antic_data
+}
+
+fn fifth_player() -> AnticData {
+    let mut antic_data = antic_data();
+    set_regs(&mut antic_data, 0..80, |regs| {
+        set_pm_regs(regs);
+        regs.prior = 0x11;
+    });
+    set_regs(&mut antic_data, 80..160, |regs| {
+        set_pm_regs(regs);
+        regs.prior = 0x14;
+    });
+    playfield(&mut antic_data, 0..160);
+    antic_data
+}
+
+fn multicolor_players() -> AnticData {
+    let mut antic_data = antic_data();
+    for (i, prior) in [0x01, 0x21, 0x24, 0x28].into_iter().enumerate() {
+        let first = i * 40;
+        set_regs(&mut antic_data, first..first + 40, |regs| {
+            set_pm_regs(regs);
+            regs.hposp = [0x60, 0x64, 0x90, 0x94];
+            regs.prior = prior;
+        });
+    }
+    playfield(&mut antic_data, 20..160);
+    antic_data
+}
+
+fn hscrol() -> AnticData {
+    let antic_data = antic_data();
+    // mode 2 lines with HSCROL enabled, LMS on every line
+    let mut dlist = vec![];
+    for i in 0..16 {
+        let address = SCREEN + i * 48;
+        dlist.extend([0x52, address as u8, (address >> 8) as u8]);
+    }
+    dlist.extend([0x41, 0x00, 0x20]);
+    let mut memory = vec![0; 0x10000];
+    memory[0x2000..0x2000 + dlist.len()].copy_from_slice(&dlist);
+
+    let regs = AnticRegs {
+        dmactl: 0x22,
+        chactl: 2,
+        chbase: (CHARSET >> 8) as u8,
+        dlist: 0x2000,
+        ..Default::default()
+    };
+    let mut display_list = DisplayList::new(&memory, regs);
+    let mut hscrol = 0;
+    while let Some(mode_line) = display_list.next() {
+        antic_data.insert_mode_line(&mode_line);
+        hscrol += 1;
+        display_list.regs.hscrol = hscrol & 0xf;
+    }
+    antic_data
+}
+
+/// Players and missiles fetched by DMA in single line (top) and two line (bottom) resolution.
+fn pm_dma() -> AnticData {
+    let mut antic_data = antic_data();
+    set_regs(&mut antic_data, 0..200, |regs| {
+        set_pm_regs(regs);
+        regs.gractl = 3;
+    });
+    let pm: Vec<u8> = (0..0x800)
+        .map(|i| 0x81u8.rotate_left(i as u32 / 3))
+        .collect();
+    antic_data.set_atari_memory(PM_AREA, &pm);
+    antic_data.set_atari_memory(0x5000, &[0x1b; 40]);
+    for scan_line in (0..200).step_by(2) {
+        let dmactl = if scan_line < 100 { 0x3e } else { 0x2e };
+        antic_data.insert_mode_line(&ModeLineDescr {
+            dmactl,
+            ..mode_line(13, scan_line, 2, 0x5000)
+        });
+    }
+    antic_data
+}
+
+/// Players in two line resolution, VDELAY set for players 1 and 3 and missiles 1 and 3.
+fn vdelay() -> AnticData {
+    let mut antic_data = antic_data();
+    set_regs(&mut antic_data, 0..200, |regs| {
+        set_pm_regs(regs);
+        regs.gractl = 3;
+        regs.vdelay = 0xaa;
+    });
+    let pm: Vec<u8> = (0..0x400)
+        .map(|i| if i & 8 == 0 { 0xff } else { 0 })
+        .collect();
+    antic_data.set_atari_memory(PM_AREA, &pm);
+    for scan_line in (0..200).step_by(8) {
+        antic_data.insert_mode_line(&ModeLineDescr {
+            dmactl: 0x2e,
+            ..mode_line(0, scan_line, 8, 0)
+        });
+    }
+    antic_data
+}
+
+/// Mode 3 descenders and CHACTL inverse, blank and reflect in modes 2 and 3.
+fn characters() -> AnticData {
+    let mut antic_data = antic_data();
+    let screen: Vec<u8> = (0..0x100).map(|i| (i as u8).wrapping_add(0x20)).collect();
+    antic_data.set_atari_memory(0x6000, &screen);
+    let mut scan_line = 0;
+    for chactl in [0, 1, 2, 3, 4, 6] {
+        for (mode, height) in [(2, 8), (3, 10)] {
+            // characters $20-$7f on the first line, $a0-$ff on the second one
+            for offset in [0, 0x80] {
+                antic_data.insert_mode_line(&ModeLineDescr {
+                    chactl,
+                    ..mode_line(mode, scan_line, height, 0x6000 + offset)
+                });
+                scan_line += height;
+                if scan_line >= 200 {
+                    return antic_data;
+                }
+            }
+        }
+    }
+    antic_data
+}
+
+/// Hires lines with single pixel, double pixel and solid patterns, mode 2 text below.
+fn hires(artifacts: ArtifactMode) -> AnticData {
+    let mut antic_data = antic_data();
+    antic_data.config.artifacts = artifacts as i32;
+    let patterns = [0xaa, 0x55, 0xcc, 0x33, 0xf0, 0xff, 0x00, 0x81];
+    let hires: Vec<u8> = (0..40 * 64)
+        .map(|i| patterns[(i / 40 / 8 + i % 40 / 5) % patterns.len()])
+        .collect();
+    antic_data.set_atari_memory(0x6000, &hires);
+    for scan_line in 0..64 {
+        antic_data.insert_mode_line(&mode_line(15, scan_line, 1, 0x6000 + scan_line * 40));
+    }
+    for scan_line in (64..200).step_by(8) {
+        antic_data.insert_mode_line(&mode_line(2, scan_line, 8, SCREEN + scan_line * 4));
+    }
+    antic_data
+}
+
+/// Background hue changing on every scan line and playfield with players, with PAL
+/// delay line emulation.
+fn pal_blending() -> AnticData {
+    let mut antic_data = antic_data();
+    antic_data.config.pal_blending = 1;
+    for scan_line in 0..200 {
+        set_regs(&mut antic_data, scan_line..scan_line + 1, |regs| {
+            set_pm_regs(regs);
+            regs.col[8] = ((scan_line * 0x10 + scan_line / 16) & 0xff) as u8;
+        });
+    }
+    for scan_line in 0..100 {
+        antic_data.insert_mode_line(&mode_line(0, scan_line, 1, 0));
+    }
+    playfield(&mut antic_data, 100..200);
+    antic_data
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.png", name))
+}
+
+fn write_png(path: &Path, pixels: &[u8]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, FRAME_WIDTH as u32, FRAME_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(pixels).unwrap();
+}
+
+fn read_png(path: &Path) -> Vec<u8> {
+    let decoder = png::Decoder::new(File::open(path).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!(
+        (info.width, info.height, info.color_type, info.bit_depth),
+        (
+            FRAME_WIDTH as u32,
+            FRAME_HEIGHT as u32,
+            png::ColorType::Rgba,
+            png::BitDepth::Eight
+        ),
+        "unexpected format of {}",
+        path.display(),
+    );
+    pixels.truncate(info.buffer_size());
+    pixels
+}
+
+/// Diff image: matching pixels dimmed, pixels differing by more than `tolerance`
+/// in magenta.
+fn diff_image(expected: &[u8], actual: &[u8], tolerance: u8) -> (Vec<u8>, usize) {
+    let mut diff = Vec::with_capacity(actual.len());
+    let mut count = 0;
+    for (e, a) in expected.chunks(4).zip(actual.chunks(4)) {
+        let close = |(e, a): (&u8, &u8)| e.max(a) - e.min(a) <= tolerance;
+        if e.iter().zip(a).all(close) {
+            diff.extend(a[..3].iter().map(|v| v / 4));
+            diff.push(255);
+        } else {
+            diff.extend([255, 0, 255, 255]);
+            count += 1;
+        }
+    }
+    (diff, count)
+}
+
+fn check(name: &str, antic_data: AnticData) {
+    let mut frame = Frame::default();
+    software::render(&antic_data, &mut frame);
+
+    let path = golden_path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_png(&path, &frame.pixels);
+        return;
+    }
+    compare(name, "", &frame.pixels, 0);
+}
+
+/// Renders `antic_data` on GPU and compares it against the golden image
+/// recorded by the software renderer.
+fn check_gpu(name: &str, antic_data: AnticData) {
+    let snapshot = Snapshot::capture(&antic_data);
+    let pixels = render_offscreen(MemoryMode::AddressSpace, |antic_data| {
+        snapshot.restore(antic_data)
+    });
+    compare(name, ".gpu", &pixels, GPU_TOLERANCE);
+}
+
+/// Compares `pixels` against golden image `name`, on mismatch writes them and the diff image
+/// to files with `suffix` appended to `name`.
+fn compare(name: &str, suffix: &str, pixels: &[u8], tolerance: u8) {
+    let path = golden_path(name);
+    assert!(
+        path.exists(),
+        "missing golden image {}, run with UPDATE_GOLDEN=1 to record it",
+        path.display()
+    );
+    let expected = read_png(&path);
+    let (diff, count) = diff_image(&expected, pixels, tolerance);
+    if count > 0 {
+        let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let actual_path = out_dir.join(format!("{}{}.actual.png", name, suffix));
+        let diff_path = out_dir.join(format!("{}{}.diff.png", name, suffix));
+        write_png(&actual_path, pixels);
+        write_png(&diff_path, &diff);
+        panic!(
+            "{}: {} pixels differ from {}\nactual: {}\ndiff: {}",
+            name,
+            count,
+            path.display(),
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
+
+#[test]
+fn golden_antic_modes() {
+    check("antic_modes", antic_modes());
+}
+
+#[test]
+fn golden_gtia_modes() {
+    check("gtia_modes", gtia_modes());
+}
+
+#[test]
+fn golden_priorities() {
+    check("priorities", priorities());
+}
+
+#[test]
+fn golden_fifth_player() {
+    check("fifth_player", fifth_player());
+}
+
+#[test]
+fn golden_multicolor_players() {
+    check("multicolor_players", multicolor_players());
+}
+
+#[test]
+fn golden_hscrol() {
+    check("hscrol", hscrol());
+}
+
+#[test]
+fn golden_characters() {
+    check("characters", characters());
+}
+
+#[test]
+fn golden_artifacts_lookup() {
+    check("artifacts_lookup", hires(ArtifactMode::Lookup));
+}
+
+#[test]
+fn golden_artifacts_signal() {
+    check("artifacts_signal", hires(ArtifactMode::Signal));
+}
+
+#[test]
+fn golden_pal_blending() {
+    check("pal_blending", pal_blending());
+}
+
+#[test]
+fn golden_vdelay() {
+    check("vdelay", vdelay());
+}
+
+#[test]
+fn golden_pm_dma() {
+    check("pm_dma", pm_dma());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_antic_modes() {
+    check_gpu("antic_modes", antic_modes());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_gtia_modes() {
+    check_gpu("gtia_modes", gtia_modes());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_priorities() {
+    check_gpu("priorities", priorities());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_fifth_player() {
+    check_gpu("fifth_player", fifth_player());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_multicolor_players() {
+    check_gpu("multicolor_players", multicolor_players());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_hscrol() {
+    check_gpu("hscrol", hscrol());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_pm_dma() {
+    check_gpu("pm_dma", pm_dma());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_vdelay() {
+    check_gpu("vdelay", vdelay());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_characters() {
+    check_gpu("characters", characters());
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_artifacts_lookup() {
+    check_gpu("artifacts_lookup", hires(ArtifactMode::Lookup));
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_artifacts_signal() {
+    check_gpu("artifacts_signal", hires(ArtifactMode::Signal));
+}
+
+#[test]
+#[ignore = "requires GPU"]
+fn gpu_pal_blending() {
+    check_gpu("pal_blending", pal_blending());
+}